@@ -10,8 +10,57 @@ if ! command -v deno &> /dev/null; then
     exit 1
 fi
 
+# ./build.sh --features local-whisper,mp3 leaves out every feature not
+# listed, and --features none keeps only self-hosted transcription; without
+# --features everything is included
+ALL_FEATURES="openai elevenlabs openrouter gemini local-whisper mp3"
+FEATURES=""
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --features)
+            FEATURES="${2:-}"
+            shift 2
+            ;;
+        --features=*)
+            FEATURES="${1#--features=}"
+            shift
+            ;;
+        *)
+            echo "❌ Unknown option: $1"
+            echo "   Usage: ./build.sh [--features feature,...]"
+            echo "   Features: ${ALL_FEATURES// /, }"
+            exit 1
+            ;;
+    esac
+done
+
+SOURCE=typr.ts
+if [ -n "$FEATURES" ]; then
+    LIST=""
+    for feature in ${FEATURES//,/ }; do
+        [ "$feature" = none ] && continue
+        if [[ " $ALL_FEATURES " != *" $feature "* ]]; then
+            echo "❌ Unknown feature: $feature"
+            echo "   Features: ${ALL_FEATURES// /, }"
+            exit 1
+        fi
+        LIST="$LIST\"$feature\", "
+    done
+    echo "🧩 Features: ${FEATURES//,/, }"
+
+    # Compile a copy with the feature list baked in
+    SOURCE=.typr-build.ts
+    trap 'rm -f .typr-build.ts' EXIT
+    sed "s/^const BUILD_FEATURES: Feature\[\] | null = null;/const BUILD_FEATURES: Feature[] | null = [${LIST%, }];/" \
+        typr.ts > "$SOURCE"
+    if ! grep -q "^const BUILD_FEATURES: Feature\[\] | null = \[" "$SOURCE"; then
+        echo "❌ Couldn't set the feature list in typr.ts"
+        exit 1
+    fi
+fi
+
 # Compile the code
-deno compile --allow-all --output dist/typr typr.ts
+deno compile --allow-all --output dist/typr "$SOURCE"
 
 # Make script executable
 chmod +x dist/typr
@@ -32,6 +81,6 @@ fi
 
 echo ""
 echo "🚀 Next steps:"
-echo "  1. Add your OpenAI key to ~/.typr-settings.json"
-echo "  2. typr shortcuts  # Setup keyboard shortcut"
+echo "  1. typr setup      # Check permissions and run a test dictation"
+echo "  2. Bind the shortcut it shows you"
 echo "  3. Use your shortcut to record!"
@@ -1,10 +1,49 @@
 #!/usr/bin/env -S deno run --allow-all
 
 import { parseArgs } from "jsr:@std/cli";
+import { decodeBase64, encodeBase64 } from "jsr:@std/encoding/base64";
 import { exists } from "jsr:@std/fs";
-import { join } from "jsr:@std/path";
+import { TextLineStream } from "jsr:@std/streams";
+import { basename, extname, join } from "jsr:@std/path";
 import { z } from "npm:zod";
 
+// Optional parts of Typr. build.sh --features rewrites BUILD_FEATURES to
+// compile a binary with only some of them, such as one that can never reach
+// a cloud provider; null includes everything. Self-hosted and Wyoming
+// servers are always available.
+const FEATURES = [
+  "openai",
+  "elevenlabs",
+  "openrouter",
+  "gemini",
+  "local-whisper",
+  "mp3",
+] as const;
+type Feature = (typeof FEATURES)[number];
+const BUILD_FEATURES: Feature[] | null = null;
+
+function hasFeature(feature: string): boolean {
+  return (
+    !(FEATURES as readonly string[]).includes(feature) ||
+    BUILD_FEATURES === null ||
+    BUILD_FEATURES.includes(feature as Feature)
+  );
+}
+
+const DEFAULT_PROFANITY_WORDS = [
+  "fuck",
+  "fucking",
+  "shit",
+  "bullshit",
+  "damn",
+  "bitch",
+  "bastard",
+  "asshole",
+  "crap",
+  "dick",
+  "piss",
+];
+
 // Default prompts
 const DEFAULT_WHISPER_PROMPT =
   "The following is a transcription of a dictation from a speaker who is XXX. The speaker sometimes discusses the following topics: YYY. The speaker sometimes uses the following uncommon terms: ZZZ.";
@@ -13,28 +52,291 @@ const DEFAULT_LLM_PROMPT =
   "You are a helpful assistant that will carefully examine the following transcription of a dictation and then carefully make the modifications requested of the editor.";
 
 // Types and interfaces
+const llmProviderSchema = z.enum(["openai", "openrouter", "gemini"]);
+const casingSchema = z.enum(["auto", "sentence", "lower", "title"]);
+const outputSchema = z.enum(["type", "inbox", "form", "scratchpad"]);
+// Email modes extract a subject and body, then type the body or open a draft
+const emailOutputSchema = z.enum(["off", "type", "mailto"]);
+
+// A mode overrides parts of the top-level settings for a kind of dictation
+// "expand" writes k8s as Kubernetes, "contract" does the reverse
+const acronymDirectionSchema = z.enum(["off", "expand", "contract"]);
+// What to do with results longer than maxLength
+const overflowSchema = z.enum(["truncate", "summarize", "split"]);
+
+// Key combos like "mod+enter", checked against the keys Typr can press
+const keyComboSchema = z.string().superRefine((combo, context) => {
+  try {
+    parseKeyCombo(combo);
+  } catch (error) {
+    context.addIssue({ code: "custom", message: (error as Error).message });
+  }
+});
+
+const urlSchema = z.string().url();
+
+function isValidLocale(locale: string): boolean {
+  try {
+    return Intl.NumberFormat.supportedLocalesOf([locale]).length > 0;
+  } catch {
+    return false;
+  }
+}
+
+const modeSchema = z.object({
+  llmPrompt: z.string().optional(),
+  prompt: z.string().optional(),
+  llmProvider: llmProviderSchema.optional(),
+  llmModel: z.string().optional(),
+  postTypeKeys: z.array(keyComboSchema).optional(),
+  casing: casingSchema.optional(),
+  punctuation: z.boolean().optional(),
+  output: outputSchema.optional(),
+  emailOutput: emailOutputSchema.optional(),
+  acronymDirection: acronymDirectionSchema.optional(),
+  useFormattingKeywords: z.boolean().optional(),
+  identifierCommands: z.boolean().optional(),
+  maxLength: z.number().int().min(0).optional(),
+  overflow: overflowSchema.optional(),
+  messageChunkSize: z.number().int().min(0).optional(),
+  messageDelayMs: z.number().int().min(0).optional(),
+  validateLLMResponse: z.boolean().optional(),
+});
+
+type Mode = z.infer<typeof modeSchema>;
+
+// Length-based provider choice: the first route whose maxSeconds covers the
+// recording (or that has none) picks the provider, e.g. a fast local model
+// for quick notes and OpenAI for long dictations
+const providerRouteSchema = z.object({
+  maxSeconds: z.number().positive().optional(),
+  provider: z.enum(["local", "openai", "elevenlabs", "selfhosted", "wyoming"]),
+  model: z.string().optional(),
+  // selfhosted only, for a second OpenAI-compatible service such as Groq
+  url: urlSchema.optional(),
+  key: z.string().optional(),
+});
+
+type ProviderRoute = z.infer<typeof providerRouteSchema>;
+
+// Vocabulary, prompts and replacements for one spoken language
+const languageProfileSchema = z.object({
+  whisperPrompt: z.string().optional(),
+  vocabulary: z.array(z.string()).default([]),
+  llmPrompt: z.string().optional(),
+  replacements: z.record(z.string()).default({}),
+});
+
+type LanguageProfile = z.infer<typeof languageProfileSchema>;
+
+// Bump when a stored setting is renamed or reshaped, and add a migration
+const SETTINGS_VERSION = 1;
+
 const settingsSchema = z.object({
+  settingsVersion: z.number().int().default(SETTINGS_VERSION),
   openAIKey: z.string().default(""),
+  openAIKeys: z.array(z.string()).default([]),
+  keyRotation: z.enum(["failover", "round-robin"]).default("failover"),
+  rateLimitMaxWaitSeconds: z.number().min(0).default(60),
+  azureEndpoint: urlSchema.or(z.literal("")).default(""),
+  azureApiVersion: z.string().default("2024-06-01"),
+  azureTranscriptionDeployment: z.string().default(""),
+  azureChatDeployment: z.string().default(""),
+  llmProvider: llmProviderSchema.default("openai"),
+  openAIModel: z.string().default("gpt-4o-mini"),
+  openRouterKey: z.string().default(""),
+  openRouterModel: z.string().default("anthropic/claude-3.5-haiku"),
+  geminiKey: z.string().default(""),
+  geminiModel: z.string().default("gemini-2.0-flash"),
+  recordingWarningSeconds: z.number().min(0).default(120),
+  maxRecordingSeconds: z.number().min(0).default(0),
+  streamingChunkSeconds: z.number().min(0).default(0),
+  longAudioChunkSeconds: z.number().min(0).default(600),
+  costConfirmThreshold: z.number().min(0).default(0),
+  transcriptionPricePerMinute: z.record(z.number().min(0)).default({}),
+  chunkConcurrency: z.number().int().min(1).default(3),
+  wakeWordCommand: z.string().default(""),
+  wakeWordSilenceSeconds: z.number().positive().default(1.5),
+  liveDictation: z.boolean().default(false),
+  liveModel: z.string().default("gpt-4o-transcribe"),
+  captionSource: z.string().default(""),
+  noiseGateDb: z.number().max(0).default(0),
+  speechThresholdDb: z.number().max(0).default(-45),
+  hallucinationFilter: z.boolean().default(true),
+  warmUp: z.boolean().default(true),
+  echoCancellation: z.boolean().default(false),
+  sidetone: z.boolean().default(false),
+  sidetoneVolume: z.number().min(0).max(2).default(0.5),
+  sidetoneDevice: z.string().default(""),
+  uploadQuality: z
+    .enum(["lossless", "high", "standard", "low"])
+    .default("lossless"),
+  pasteThreshold: z.number().int().min(0).default(200),
+  releaseGraceMs: z.number().int().min(0).max(3000).default(0),
+  // What holding Shift while stopping a recording does to that dictation
+  shiftAction: z.enum(["none", "copy-only", "skip-llm"]).default("none"),
+  // Keep each recording for `typr review` (trim, then send) instead of
+  // transcribing it straight away
+  reviewRecordings: z.boolean().default(false),
+  transientClipboard: z.boolean().default(false),
+  output: outputSchema.default("type"),
+  emailOutput: emailOutputSchema.default("off"),
+  inboxFile: z.string().default("~/inbox.md"),
+  scratchpadFile: z.string().default("~/.typr-scratchpad.md"),
+  sessionDir: z.string().default("~/typr-sessions"),
+  // Recordings longer than this are processed in the background and saved
+  // to backgroundDir instead of being typed
+  backgroundAfterSeconds: z.number().min(0).default(300),
+  backgroundDir: z.string().default("~/typr-transcripts"),
+  // Voice memos synced from a phone (iCloud Drive, Syncthing) for typr import
+  importDir: z.string().default(""),
+  summarizeSessions: z.boolean().default(false),
+  macTypingBackend: z.enum(["cgevent", "applescript"]).default("cgevent"),
+  linuxTypingBackend: z.enum(["auto", "xdotool", "wtype"]).default("auto"),
+  xdotoolDelay: z.number().int().min(0).default(12),
+  keyCommands: z.boolean().default(false),
+  // Spoken words replaced before the LLM sees the text
+  formattingKeywords: z.record(z.string()).default({ slap: "\n" }),
+  useFormattingKeywords: z.boolean().default(true),
+  // "camel case user id" -> userId
+  identifierCommands: z.boolean().default(false),
+  // "john dot smith at gmail dot com" -> john.smith@gmail.com
+  normalizeAddresses: z.boolean().default(false),
+  // Reformat numbers for a locale, e.g. "de-DE" writes 1.234,5 (empty keeps
+  // them as transcribed)
+  numberLocale: z
+    .string()
+    .refine((locale) => !locale || isValidLocale(locale), {
+      message: "Not a locale Intl supports (e.g. \"de-DE\")",
+    })
+    .default(""),
+  numberGrouping: z.boolean().default(true),
+  spokenFractions: z.enum(["keep", "glyph", "slash"]).default("keep"),
+  postTypeKeys: z.array(keyComboSchema).default([]),
+  maxLength: z.number().int().min(0).default(0),
+  overflow: overflowSchema.default("split"),
+  // Chat apps: send the result as messages of whole sentences, at most this
+  // many characters each
+  messageChunkSize: z.number().int().min(0).default(0),
+  messageDelayMs: z.number().int().min(0).default(500),
+  appPostTypeKeys: z.record(z.array(keyComboSchema)).default({}),
+  focusChangeBehavior: z
+    .enum(["refocus", "warn", "hold", "ignore"])
+    .default("refocus"),
+  readBack: z.enum(["off", "system", "openai"]).default("off"),
+  readBackVoice: z.string().default("alloy"),
+  paused: z.boolean().default(false),
+  skipLLM: z.boolean().default(false),
+  validateLLMResponse: z.boolean().default(true),
+  historyLimit: z.number().int().min(0).default(500),
+  recordingRetentionHours: z.number().min(0).default(24),
+  syncDir: z.string().default(""),
+  syncPassphrase: z.string().default(""),
+  modes: z.record(modeSchema).default({}),
+  activeMode: z.string().default("default"),
   whisperPrompt: z.string().default(DEFAULT_WHISPER_PROMPT),
   llmPrompt: z.string().default(DEFAULT_LLM_PROMPT),
+  // Name of a prompt library entry ("name" or "name@version") to use
+  // instead of llmPrompt
+  prompt: z.string().default(""),
+  language: z.string().default("en"),
+  languageProfiles: z.record(languageProfileSchema).default({}),
+  // With language "auto": languages to accept (empty accepts all), and
+  // whether others are refused or only flagged
+  allowedLanguages: z.array(z.string()).default([]),
+  otherLanguages: z.enum(["refuse", "flag"]).default("refuse"),
+  replacements: z.record(z.string()).default({}),
+  casing: casingSchema.default("auto"),
+  punctuation: z.boolean().default(true),
+  capitalizeI: z.boolean().default(true),
+  profanityFilter: z.enum(["off", "mask", "drop"]).default("off"),
+  profanityWords: z.array(z.string()).default(DEFAULT_PROFANITY_WORDS),
+  vocabulary: z.array(z.string()).default([]),
+  vocabularyFile: z.string().default(""),
+  screenContext: z.enum(["off", "accessibility", "ocr"]).default("off"),
+  contactsFile: z.string().default(""),
+  entityMatching: z.boolean().default(true),
+  acronyms: z.record(z.string()).default({}),
+  acronymDirection: acronymDirectionSchema.default("off"),
   useLocalWhisper: z.boolean().default(false),
+  localWhisperFlavor: z
+    .enum(["openai-whisper", "whisper.cpp", "whisperx"])
+    .default("openai-whisper"),
+  localWhisperPath: z.string().default(""),
+  localWhisperModel: z.string().default("base"),
+  localWhisperArgs: z.array(z.string()).default([]),
+  localWhisperOutputDir: z.string().default("/tmp"),
+  // whisperx only: bias decoding towards the vocabulary via --hotwords
+  localWhisperHotwords: z.boolean().default(false),
+  transcriptionProvider: z
+    .enum(["openai", "elevenlabs", "selfhosted", "wyoming"])
+    .default("openai"),
+  openAITranscriptionModel: z.string().default("whisper-1"),
+  providerRoutes: z.array(providerRouteSchema).default([]),
+  transcriptCache: z.boolean().default(true),
+  // Whisper segments averaging a lower log probability get transcribed again
+  // with lowConfidenceModel (0 disables)
+  minAvgLogprob: z.number().max(0).default(0),
+  lowConfidenceModel: z.string().default("gpt-4o-transcribe"),
+  elevenLabsKey: z.string().default(""),
+  elevenLabsModel: z.string().default("scribe_v1"),
+  elevenLabsLanguage: z.string().default(""),
+  elevenLabsTagAudioEvents: z.boolean().default(false),
+  selfHostedURL: urlSchema.default("http://localhost:8000/v1"),
+  selfHostedModel: z.string().default("Systran/faster-whisper-small"),
+  selfHostedKey: z.string().default(""),
+  wyomingHost: z.string().default("homeassistant.local"),
+  wyomingPort: z.number().int().default(10300),
+  wyomingLanguage: z.string().default("en"),
+  logLevel: z.enum(["debug", "info", "error"]).default("info"),
+  providerLogging: z.boolean().default(false),
+  eventLog: z.boolean().default(false),
+  eventStreamEnabled: z.boolean().default(false),
+  eventStreamPort: z.number().int().default(7717),
+  eventStreamPartials: z.boolean().default(false),
+  remoteMicEnabled: z.boolean().default(false),
+  remoteMicToken: z.string().default(""),
 });
 
 type Settings = z.infer<typeof settingsSchema>;
 
 // Logging system
 const LOG_FILE = join(Deno.env.get("HOME") || ".", ".typr-log.txt");
+const LOG_MAX_BYTES = 1024 * 1024;
+
+type LogLevel = "DEBUG" | "INFO" | "ERROR";
+const LOG_LEVELS: LogLevel[] = ["DEBUG", "INFO", "ERROR"];
+
+// Read straight from the settings file: loadSettings itself logs
+let minimumLogLevel: LogLevel | null = null;
+async function getMinimumLogLevel(): Promise<LogLevel> {
+  if (minimumLogLevel) return minimumLogLevel;
+  try {
+    const raw = JSON.parse(await Deno.readTextFile(SETTINGS_FILE));
+    minimumLogLevel =
+      LOG_LEVELS.find(
+        (level) => level === String(raw.logLevel).toUpperCase()
+      ) ?? "INFO";
+  } catch {
+    minimumLogLevel = "INFO";
+  }
+  return minimumLogLevel;
+}
 
 async function logToFile(
-  level: "INFO" | "ERROR",
+  level: LogLevel,
   message: string,
   error?: unknown
 ): Promise<void> {
+  const minimum = await getMinimumLogLevel();
+  if (LOG_LEVELS.indexOf(level) < LOG_LEVELS.indexOf(minimum)) return;
+
   const timestamp = new Date().toISOString();
   const logMessage = `[${timestamp}] ${level}: ${message}${
     error ? ` - ${error}` : ""
   }\n`;
   try {
+    await rotateIfLarge(LOG_FILE, LOG_MAX_BYTES);
     await Deno.writeTextFile(LOG_FILE, logMessage, { append: true });
   } catch (e) {
     // Fallback to console if logging fails
@@ -42,9 +344,224 @@ async function logToFile(
   }
 }
 
+// Opt-in record of every provider request and response, for bug reports
+const PROVIDER_LOG_FILE = join(
+  Deno.env.get("HOME") || ".",
+  ".typr-provider-log.jsonl"
+);
+const PROVIDER_LOG_MAX_BYTES = 5 * 1024 * 1024;
+
+// Opt-in JSON lines record of every dictation: timings, sizes and error codes
+// but never the text itself
+const EVENT_LOG_FILE = join(Deno.env.get("HOME") || ".", ".typr-events.jsonl");
+const EVENT_LOG_MAX_BYTES = 5 * 1024 * 1024;
+
+// Keep one previous generation next to the live file
+async function rotateIfLarge(path: string, maxBytes: number): Promise<void> {
+  try {
+    const { size } = await Deno.stat(path);
+    if (size > maxBytes) await Deno.rename(path, `${path}.1`);
+  } catch {
+    // Nothing to rotate yet
+  }
+}
+
+const REDACTED_HEADERS = [
+  "authorization",
+  "api-key",
+  "xi-api-key",
+  "x-goog-api-key",
+];
+
+function redactHeaders(
+  headers: HeadersInit | undefined
+): Record<string, string> {
+  const redacted: Record<string, string> = {};
+  new Headers(headers).forEach((value, name) => {
+    redacted[name] = REDACTED_HEADERS.includes(name) ? "[redacted]" : value;
+  });
+  return redacted;
+}
+
+// Audio never goes into the log, only its size
+function describeBody(body: BodyInit | null | undefined): unknown {
+  if (body instanceof FormData) {
+    const fields: Record<string, string> = {};
+    body.forEach((value, name) => {
+      fields[name] =
+        typeof value === "string" ? value : `<${value.size} bytes of audio>`;
+    });
+    return fields;
+  }
+  if (typeof body === "string") {
+    try {
+      return JSON.parse(body);
+    } catch {
+      return body;
+    }
+  }
+  return body ? "<binary>" : null;
+}
+
+// fetch() for transcription, LLM and speech providers, logging the exchange
+// when providerLogging is on
+async function providerFetch(
+  url: string,
+  init: RequestInit
+): Promise<Response> {
+  const { providerLogging } = await loadSettings();
+  const started = Date.now();
+  const response = await fetch(url, init);
+  if (!providerLogging) return response;
+
+  const type = response.headers.get("content-type") ?? "";
+  const body = type.startsWith("audio/")
+    ? `<${type} audio>`
+    : await response.clone().text();
+  try {
+    await rotateIfLarge(PROVIDER_LOG_FILE, PROVIDER_LOG_MAX_BYTES);
+    const entry = {
+      timestamp: new Date().toISOString(),
+      durationMs: Date.now() - started,
+      request: {
+        method: init.method ?? "GET",
+        url: url.replace(/([?&]key=)[^&]+/, "$1[redacted]"),
+        headers: redactHeaders(init.headers),
+        body: describeBody(init.body),
+      },
+      response: { status: response.status, body },
+    };
+    await Deno.writeTextFile(
+      PROVIDER_LOG_FILE,
+      JSON.stringify(entry) + "\n",
+      { append: true }
+    );
+  } catch (error) {
+    await logToFile("ERROR", "Failed to write provider log", error);
+  }
+  return response;
+}
+
 // Settings management
 const SETTINGS_FILE = join(Deno.env.get("HOME") || ".", ".typr-settings.json");
 const STATE_FILE = join(Deno.env.get("HOME") || ".", ".typr-state.json");
+const USAGE_FILE = join(Deno.env.get("HOME") || ".", ".typr-usage.json");
+const HISTORY_FILE = join(Deno.env.get("HOME") || ".", ".typr-history.json");
+const METRICS_FILE = join(Deno.env.get("HOME") || ".", ".typr-metrics.json");
+const RECORDING_LOCK_FILE = join(
+  Deno.env.get("HOME") || ".",
+  ".typr-recording.lock"
+);
+const INJECTION_FILE = join(
+  Deno.env.get("HOME") || ".",
+  ".typr-last-injection.json"
+);
+const CORRECTIONS_FILE = join(
+  Deno.env.get("HOME") || ".",
+  ".typr-corrections.json"
+);
+const SESSION_FILE = join(Deno.env.get("HOME") || ".", ".typr-session.json");
+const PENDING_FILE = join(Deno.env.get("HOME") || ".", ".typr-pending.json");
+const REVIEW_FILE = join(Deno.env.get("HOME") || ".", ".typr-review.json");
+// Outside /tmp, where old typr-* recordings are cleaned up
+const REVIEW_AUDIO_FILE = join(
+  Deno.env.get("HOME") || ".",
+  ".typr-review.wav"
+);
+const TRANSCRIPT_CACHE_FILE = join(
+  Deno.env.get("HOME") || ".",
+  ".typr-transcript-cache.json"
+);
+const PROMPTS_FILE = join(Deno.env.get("HOME") || ".", ".typr-prompts.json");
+const IMPORTED_FILE = join(
+  Deno.env.get("HOME") || ".",
+  ".typr-imported.json"
+);
+
+type RawSettings = Record<string, unknown>;
+
+// migrations[n] upgrades a version n settings file to version n + 1
+const SETTINGS_MIGRATIONS: ((raw: RawSettings) => RawSettings)[] = [
+  // The desktop app stored prompts as customVocabulary/customInstructions
+  ({ customVocabulary, customInstructions, ...rest }) => ({
+    ...rest,
+    whisperPrompt:
+      rest.whisperPrompt ??
+      (Array.isArray(customVocabulary)
+        ? customVocabulary.join(", ")
+        : customVocabulary),
+    llmPrompt: rest.llmPrompt ?? customInstructions,
+  }),
+];
+
+async function migrateSettings(raw: RawSettings): Promise<RawSettings> {
+  const version =
+    typeof raw.settingsVersion === "number" ? raw.settingsVersion : 0;
+  if (version >= SETTINGS_VERSION) return raw;
+
+  // Keep the original around in case a migration gets something wrong
+  const backup = `${SETTINGS_FILE}.v${version}.bak`;
+  await Deno.copyFile(SETTINGS_FILE, backup);
+
+  let migrated = raw;
+  for (let from = version; from < SETTINGS_VERSION; from++) {
+    migrated = SETTINGS_MIGRATIONS[from](migrated);
+  }
+  migrated.settingsVersion = SETTINGS_VERSION;
+
+  await Deno.writeTextFile(SETTINGS_FILE, JSON.stringify(migrated, null, 2));
+  await logToFile(
+    "INFO",
+    `Migrated settings from v${version} to v${SETTINGS_VERSION} (backup: ${backup})`
+  );
+  return migrated;
+}
+
+// Field-level problems in the settings file ("selfHostedURL: Invalid url"),
+// reported together instead of failing on the first one
+class SettingsError extends Error {
+  constructor(readonly issues: string[]) {
+    super(
+      `Invalid settings in ${SETTINGS_FILE}:\n${issues
+        .map((issue) => `  - ${issue}`)
+        .join("\n")}`
+    );
+  }
+}
+
+// checkReferences also looks up names that point into other files, which
+// `typr config --check` reports but loading tolerates
+async function validateSettingsFile(checkReferences = false): Promise<
+  { success: true; settings: Settings } | { success: false; issues: string[] }
+> {
+  let raw: RawSettings;
+  try {
+    raw = JSON.parse(await Deno.readTextFile(SETTINGS_FILE));
+  } catch (error) {
+    return { success: false, issues: [`not valid JSON (${error})`] };
+  }
+
+  // Upgrade older files first
+  const result = settingsSchema.safeParse(await migrateSettings(raw));
+  if (result.success) {
+    const issues = checkReferences
+      ? await promptReferenceIssues(result.data)
+      : [];
+    if (issues.length > 0) return { success: false, issues };
+    return { success: true, settings: result.data };
+  }
+  return {
+    success: false,
+    issues: result.error.issues.map(
+      (issue) => `${issue.path.join(".") || "(file)"}: ${issue.message}`
+    ),
+  };
+}
+
+// Parsed settings, reused until the file changes on disk. A dictation loads
+// settings many times, and long-running commands (serve, listen) pick up
+// edits without a restart.
+let settingsCache: { version: string; settings: Settings } | null = null;
 
 async function loadSettings(): Promise<Settings> {
   // If the file doesn't exist, create it
@@ -55,9 +572,278 @@ async function loadSettings(): Promise<Settings> {
     );
   }
 
-  // Load the settings
-  const data = await Deno.readTextFile(SETTINGS_FILE);
-  return settingsSchema.parse(JSON.parse(data));
+  const { mtime, size } = await Deno.stat(SETTINGS_FILE);
+  const version = `${mtime?.getTime()}:${size}`;
+  if (settingsCache?.version !== version) {
+    const result = await validateSettingsFile();
+    if (!result.success) throw new SettingsError(result.issues);
+    settingsCache = { version, settings: result.settings };
+    minimumLogLevel = null;
+  }
+  // Callers adjust what they get, so each gets its own copy
+  return structuredClone(settingsCache.settings);
+}
+
+// Settings for the next dictation: the active (or given) mode, language
+// profile and vocabulary file all applied, and whisperPrompt composed into
+// its final form
+async function loadActiveSettings(mode?: string): Promise<Settings> {
+  const settings = await loadSettings();
+  if (mode !== undefined) settings.activeMode = mode;
+  const config = await applyPromptLibrary(
+    await applyVocabularyFile(applyMode(settings))
+  );
+  return { ...config, whisperPrompt: composeWhisperPrompt(config) };
+}
+
+function expandHome(path: string): string {
+  return path.replace(/^~(?=$|\/)/, Deno.env.get("HOME") || "~");
+}
+
+type Vocabulary = { terms: string[]; replacements: Record<string, string> };
+
+// One term per line; in CSV files a second column turns the row into a
+// replacement ("kay eight s,k8s"). Lines starting with # are comments.
+function parseVocabulary(content: string, csv: boolean): Vocabulary {
+  const vocabulary: Vocabulary = { terms: [], replacements: {} };
+  for (const raw of content.split("\n")) {
+    const line = raw.trim();
+    if (!line || line.startsWith("#")) continue;
+    if (!csv) {
+      vocabulary.terms.push(line);
+      continue;
+    }
+    const [term, written] = line
+      .split(",")
+      .map((cell) => cell.trim().replace(/^"(.*)"$/, "$1"));
+    if (written) {
+      vocabulary.replacements[term] = written;
+      vocabulary.terms.push(written);
+    } else if (term) {
+      vocabulary.terms.push(term);
+    }
+  }
+  return vocabulary;
+}
+
+// The file is read fresh for every dictation, so edits apply straight away
+async function applyVocabularyFile(config: Settings): Promise<Settings> {
+  if (!config.vocabularyFile) return config;
+
+  const path = expandHome(config.vocabularyFile);
+  let content: string;
+  try {
+    content = await Deno.readTextFile(path);
+  } catch (error) {
+    await logToFile("ERROR", `Failed to read vocabulary file ${path}`, error);
+    return config;
+  }
+
+  const { terms, replacements } = parseVocabulary(
+    content,
+    extname(path).toLowerCase() === ".csv"
+  );
+  return {
+    ...config,
+    replacements: { ...config.replacements, ...replacements },
+    vocabulary: [...config.vocabulary, ...terms],
+  };
+}
+
+// Named LLM prompts, every saved version kept so a change can be compared
+// with or reverted to an earlier one
+const promptVersionSchema = z.object({
+  version: z.number().int().positive(),
+  savedAt: z.string(),
+  text: z.string(),
+});
+
+const promptLibrarySchema = z.record(z.array(promptVersionSchema));
+
+type PromptVersion = z.infer<typeof promptVersionSchema>;
+type PromptLibrary = z.infer<typeof promptLibrarySchema>;
+
+async function loadPromptLibrary(): Promise<PromptLibrary> {
+  try {
+    return promptLibrarySchema.parse(
+      JSON.parse(await Deno.readTextFile(PROMPTS_FILE))
+    );
+  } catch {
+    return {};
+  }
+}
+
+async function savePromptLibrary(library: PromptLibrary): Promise<void> {
+  await Deno.writeTextFile(PROMPTS_FILE, JSON.stringify(library, null, 2));
+}
+
+// "name" is the latest version, "name@3" pins version 3
+function findPrompt(
+  library: PromptLibrary,
+  reference: string
+): PromptVersion | null {
+  const [name, version] = reference.split("@");
+  const versions = library[name] ?? [];
+  if (version === undefined) return versions[versions.length - 1] ?? null;
+  return versions.find((entry) => entry.version === Number(version)) ?? null;
+}
+
+// Prompt references in the settings and modes that the library can't resolve
+async function promptReferenceIssues(settings: Settings): Promise<string[]> {
+  const library = await loadPromptLibrary();
+  const references: [string, string | undefined][] = [
+    ["prompt", settings.prompt],
+    ...Object.entries(settings.modes).map(
+      ([name, mode]): [string, string | undefined] => [
+        `modes.${name}.prompt`,
+        mode.prompt,
+      ]
+    ),
+  ];
+  return references
+    .filter(([, reference]) => reference && !findPrompt(library, reference))
+    .map(
+      ([path, reference]) => `${path}: no prompt ${reference} in the library`
+    );
+}
+
+async function applyPromptLibrary(config: Settings): Promise<Settings> {
+  if (!config.prompt) return config;
+
+  const found = findPrompt(await loadPromptLibrary(), config.prompt);
+  if (!found) {
+    await logToFile(
+      "ERROR",
+      `Prompt ${config.prompt} is not in ${PROMPTS_FILE}, using llmPrompt`
+    );
+    return config;
+  }
+  return { ...config, llmPrompt: found.text };
+}
+
+// Vocabulary terms without blanks and case-insensitive duplicates
+function vocabularyTerms(config: Settings): string[] {
+  const seen = new Set<string>();
+  return config.vocabulary.filter((term) => {
+    const key = term.trim().toLowerCase();
+    if (!key || seen.has(key)) return false;
+    seen.add(key);
+    return true;
+  });
+}
+
+// The prompt Whisper actually sees: the raw whisperPrompt followed by the
+// vocabulary (settings, language profile and vocabulary file, de-duplicated)
+function composeWhisperPrompt(config: Settings): string {
+  const terms = vocabularyTerms(config);
+  return [
+    config.whisperPrompt.trim(),
+    terms.length ? `Vocabulary: ${terms.join(", ")}.` : "",
+  ]
+    .filter((part) => part.length > 0)
+    .join("\n\n");
+}
+
+async function saveSettings(settings: Settings): Promise<void> {
+  await Deno.writeTextFile(SETTINGS_FILE, JSON.stringify(settings, null, 2));
+  settingsCache = null;
+}
+
+// Settings with the active mode's overrides applied
+function applyMode(config: Settings): Settings {
+  const mode: Mode | undefined = config.modes[config.activeMode];
+  if (!mode) return config;
+
+  const resolved = { ...config };
+  if (mode.llmPrompt !== undefined) resolved.llmPrompt = mode.llmPrompt;
+  if (mode.prompt !== undefined) resolved.prompt = mode.prompt;
+  if (mode.llmProvider) resolved.llmProvider = mode.llmProvider;
+  if (mode.postTypeKeys) resolved.postTypeKeys = mode.postTypeKeys;
+  if (mode.casing) resolved.casing = mode.casing;
+  if (mode.punctuation !== undefined) resolved.punctuation = mode.punctuation;
+  if (mode.output) resolved.output = mode.output;
+  if (mode.emailOutput) resolved.emailOutput = mode.emailOutput;
+  if (mode.acronymDirection) resolved.acronymDirection = mode.acronymDirection;
+  if (mode.useFormattingKeywords !== undefined) {
+    resolved.useFormattingKeywords = mode.useFormattingKeywords;
+  }
+  if (mode.identifierCommands !== undefined) {
+    resolved.identifierCommands = mode.identifierCommands;
+  }
+  if (mode.maxLength !== undefined) resolved.maxLength = mode.maxLength;
+  if (mode.overflow) resolved.overflow = mode.overflow;
+  if (mode.messageChunkSize !== undefined) {
+    resolved.messageChunkSize = mode.messageChunkSize;
+  }
+  if (mode.messageDelayMs !== undefined) {
+    resolved.messageDelayMs = mode.messageDelayMs;
+  }
+  if (mode.validateLLMResponse !== undefined) {
+    resolved.validateLLMResponse = mode.validateLLMResponse;
+  }
+  if (mode.llmModel) {
+    switch (resolved.llmProvider) {
+      case "openai":
+        resolved.openAIModel = mode.llmModel;
+        break;
+      case "openrouter":
+        resolved.openRouterModel = mode.llmModel;
+        break;
+      case "gemini":
+        resolved.geminiModel = mode.llmModel;
+        break;
+    }
+  }
+  return applyLanguage(resolved, resolved.language);
+}
+
+// Providers report languages as codes ("de", "deu") or names ("german"), so
+// compare them by English display name
+function languageName(language: string): string {
+  try {
+    const names = new Intl.DisplayNames(["en"], { type: "language" });
+    return (names.of(language) ?? language).toLowerCase();
+  } catch {
+    return language.toLowerCase();
+  }
+}
+
+function isLanguageAllowed(config: Settings, language: string): boolean {
+  if (config.allowedLanguages.length === 0) return true;
+  const name = languageName(language);
+  return config.allowedLanguages.some(
+    (allowed) => languageName(allowed) === name
+  );
+}
+
+function findLanguageProfile(
+  config: Settings,
+  language: string
+): LanguageProfile | undefined {
+  const name = languageName(language);
+  const key = Object.keys(config.languageProfiles).find(
+    (key) => languageName(key) === name
+  );
+  return key === undefined ? undefined : config.languageProfiles[key];
+}
+
+// Settings with a language profile's prompts and replacements applied; a
+// mode's own LLM prompt still wins
+function applyLanguage(config: Settings, language: string): Settings {
+  const profile = findLanguageProfile(config, language);
+  if (!profile) return config;
+
+  const resolved = { ...config };
+  if (profile.whisperPrompt !== undefined) {
+    resolved.whisperPrompt = profile.whisperPrompt;
+  }
+  const mode: Mode | undefined = config.modes[config.activeMode];
+  if (profile.llmPrompt !== undefined && mode?.llmPrompt === undefined) {
+    resolved.llmPrompt = profile.llmPrompt;
+  }
+  resolved.replacements = { ...config.replacements, ...profile.replacements };
+  resolved.vocabulary = [...config.vocabulary, ...profile.vocabulary];
+  return resolved;
 }
 
 // Get and set state
@@ -76,8 +862,9 @@ async function getState(key: string): Promise<string | null> {
 }
 
 async function setState(key: string, value: string): Promise<void> {
-  const data = await Deno.readTextFile(STATE_FILE);
-  const state = JSON.parse(data);
+  const state = (await exists(STATE_FILE))
+    ? JSON.parse(await Deno.readTextFile(STATE_FILE))
+    : {};
   state[key] = value;
   await Deno.writeTextFile(STATE_FILE, JSON.stringify(state, null, 2));
 }
@@ -86,516 +873,6780 @@ async function clearState(): Promise<void> {
   await Deno.writeTextFile(STATE_FILE, JSON.stringify({}, null, 2));
 }
 
-// Notification functions
-async function notify(
-  message: string,
-  urgency: "low" | "normal" | "critical" = "normal"
-): Promise<void> {
-  try {
-    if (Deno.build.os === "darwin") {
-      // Use macOS native notifications
-      const script = `display notification "${message.replace(
-        /"/g,
-        '\\"'
-      )}" with title "Typr"`;
-      const command = new Deno.Command("osascript", { args: ["-e", script] });
-      await command.output();
-    } else {
-      // Use desktop notifications for Linux
-      const command = new Deno.Command("notify-send", {
-        args: ["--urgency", urgency, "--app-name", "Typr", message],
+// Toggle lifecycle, kept in the state file so every process agrees on it:
+// idle -> starting -> recording -> processing -> idle, or error on failure
+type Phase = "idle" | "starting" | "recording" | "processing" | "error";
+
+// How long the error phase shows before falling back to idle
+const ERROR_PHASE_MS = 5000;
+
+async function getPhase(): Promise<Phase> {
+  const phase = ((await getState("phase")) as Phase | null) ?? "idle";
+  // The process that failed may have died before resetting
+  const since = Number(await getState("phaseAt"));
+  if (phase === "error" && Date.now() - since > ERROR_PHASE_MS) return "idle";
+  return phase;
+}
+
+// The only place the phase changes, so `typr status` and event stream
+// subscribers (status bar indicators) always see the same thing
+async function setPhase(phase: Phase): Promise<void> {
+  if (phase === "idle") {
+    await clearState();
+  } else {
+    await setState("phase", phase);
+    await setState("phaseAt", String(Date.now()));
+  }
+  await logToFile("DEBUG", `Phase: ${phase}`);
+  await publishEvent(await loadSettings(), {
+    type: "phase",
+    phase,
+    timestamp: new Date().toISOString(),
+  });
+
+  if (phase === "error") {
+    await new Promise((resolve) => setTimeout(resolve, ERROR_PHASE_MS));
+    if ((await getPhase()) === "error") await setPhase("idle");
+  }
+}
+
+// Called once ffmpeg is up. A toggle that arrived while we were still
+// starting asked to stop, so honour it now instead of recording forever.
+async function markRecording(pid: number): Promise<void> {
+  await setState("ffmpegPid", pid.toString());
+  await setPhase("recording");
+  if (await getState("stopRequested")) {
+    await logToFile("INFO", "Stop requested while starting, stopping now");
+    Deno.kill(pid, "SIGTERM");
+  }
+}
+
+// Only one process records at a time; the lock holds the owner's PID so a
+// crashed owner doesn't block recording forever
+async function acquireRecordingLock(): Promise<boolean> {
+  for (let attempt = 0; attempt < 2; attempt++) {
+    try {
+      await Deno.writeTextFile(RECORDING_LOCK_FILE, String(Deno.pid), {
+        createNew: true,
       });
-      await command.output();
+      return true;
+    } catch (error) {
+      if (!(error instanceof Deno.errors.AlreadyExists)) throw error;
     }
-  } catch (error) {
-    // Fallback to console if notifications fail
-    console.log(`🔔 ${message}`);
-    await logToFile("ERROR", "Failed to send notification", error);
+
+    const owner = (await Deno.readTextFile(RECORDING_LOCK_FILE)).trim();
+    if (owner && (await isProcessAlive(owner))) return false;
+    await logToFile("INFO", `Removing stale recording lock from ${owner}`);
+    await Deno.remove(RECORDING_LOCK_FILE).catch(() => {});
   }
+  return false;
 }
 
-// Audio feedback functions
-async function playBeep(): Promise<void> {
+async function releaseRecordingLock(): Promise<void> {
   try {
-    // Use system bell - simple, universal, and reliable
-    const command = new Deno.Command("printf", { args: ["\\a"] });
-    await command.output();
-  } catch (error) {
-    // Silent fallback - don't let beep failures break recording
-    await logToFile("ERROR", "Failed to play system bell", error);
+    const owner = (await Deno.readTextFile(RECORDING_LOCK_FILE)).trim();
+    if (owner === String(Deno.pid)) await Deno.remove(RECORDING_LOCK_FILE);
+  } catch {
+    // Already gone
   }
 }
 
-async function playDoubleBeep(): Promise<void> {
-  await playBeep();
-  // Short pause between beeps
-  await new Promise((resolve) => setTimeout(resolve, 150));
-  await playBeep();
-}
-
-// Audio recording functions
-async function isWhisperAvailable(): Promise<boolean> {
+async function isProcessAlive(pid: string): Promise<boolean> {
   try {
-    const command = new Deno.Command("whisper", { args: ["--help"] });
-    const process = command.spawn();
-    const status = await process.status;
-    return status.success;
+    const { success } = await new Deno.Command("ps", {
+      args: ["-p", pid],
+      stdout: "null",
+    }).output();
+    return success;
   } catch {
     return false;
   }
 }
 
-async function startRecording(): Promise<string | null> {
-  setState("isRecording", "true");
+// Dictation history
+// Whether the editing step ran: "failed" means the raw transcript was used
+const llmStatusSchema = z.enum(["ok", "failed", "skipped"]);
 
-  // Clean up old recordings to prevent /tmp from filling up
+type LLMStatus = z.infer<typeof llmStatusSchema>;
+
+const historyEntrySchema = z.object({
+  id: z.string(),
+  timestamp: z.string(),
+  host: z.string().default(""),
+  mode: z.string().default("default"),
+  transcription: z.string(),
+  text: z.string(),
+  llmStatus: llmStatusSchema.default("skipped"),
+  // 2 when a low-confidence transcript was replaced by a retry
+  attempt: z.number().int().default(1),
+  // File name of an imported voice memo
+  source: z.string().optional(),
+});
+
+type HistoryEntry = z.infer<typeof historyEntrySchema>;
+
+async function loadHistory(): Promise<HistoryEntry[]> {
   try {
-    const command = new Deno.Command("find", {
-      args: [
-        "/tmp",
-        "-name",
-        "typr-recording-*.wav",
-        "-mtime",
-        "+1",
-        "-delete",
-      ],
-    });
-    await command.output();
-  } catch (e) {
-    logToFile("ERROR", "Failed to clean up old recordings", e);
+    const data = JSON.parse(await Deno.readTextFile(HISTORY_FILE));
+    return z.array(historyEntrySchema).parse(data);
+  } catch {
+    return [];
   }
+}
 
-  const audioPath = `/tmp/typr-recording-${new Date()
-    .toISOString()
-    .replace(/[:.]/g, "-")}.wav`;
+// Returns the entries that were kept
+async function saveHistory(
+  entries: HistoryEntry[],
+  limit: number
+): Promise<HistoryEntry[]> {
+  const sorted = [...entries].sort((a, b) =>
+    a.timestamp.localeCompare(b.timestamp)
+  );
+  // slice(-0) would keep everything; a limit of 0 means history is off
+  const kept = limit > 0 ? sorted.slice(-limit) : [];
+  await Deno.writeTextFile(HISTORY_FILE, JSON.stringify(kept, null, 2));
+  return kept;
+}
 
-  logToFile("INFO", `Recording to ${audioPath}`);
-  setState("audioPath", audioPath);
+async function addHistoryEntry(
+  config: Settings,
+  transcription: string,
+  text: string,
+  llmStatus: LLMStatus,
+  attempt = 1,
+  memo: { source: string; timestamp: string } | null = null
+): Promise<boolean> {
+  if (config.historyLimit === 0) return false;
+  const history = await loadHistory();
+  const id = crypto.randomUUID();
+  history.push({
+    id,
+    timestamp: memo?.timestamp ?? new Date().toISOString(),
+    host: Deno.hostname(),
+    mode: config.activeMode,
+    transcription,
+    text,
+    llmStatus,
+    attempt,
+    ...(memo ? { source: memo.source } : {}),
+  });
+  // A backdated memo can fall outside the newest historyLimit entries
+  const kept = await saveHistory(history, config.historyLimit);
+  return kept.some((entry) => entry.id === id);
+}
 
-  // Use ffmpeg for cross-platform audio recording
-  const inputArgs =
-    Deno.build.os === "darwin"
-      ? ["-f", "avfoundation", "-i", ":0"] // macOS microphone
-      : ["-f", "pulse", "-i", "default"]; // Linux
+// Encrypted sync through a shared folder (iCloud Drive, Dropbox, Syncthing).
+// Each machine writes its own file so concurrent syncs never clobber each
+// other; the folder only ever sees AES-GCM ciphertext.
+const syncPayloadSchema = z.object({
+  history: z.array(historyEntrySchema).default([]),
+  modes: z.record(modeSchema).default({}),
+});
 
-  const ffmpegArgs = [
-    ...inputArgs,
-    "-acodec",
-    "pcm_s16le",
-    "-ar",
-    "16000",
-    "-ac",
-    "1",
-    "-f",
-    "wav", // Explicitly specify WAV format
-    "-y", // Overwrite output file
-    audioPath,
-  ];
-
-  logToFile("INFO", `ffmpegArgs: ${ffmpegArgs}`);
+async function deriveSyncKey(
+  passphrase: string,
+  salt: Uint8Array
+): Promise<CryptoKey> {
+  const material = await crypto.subtle.importKey(
+    "raw",
+    new TextEncoder().encode(passphrase),
+    "PBKDF2",
+    false,
+    ["deriveKey"]
+  );
+  return await crypto.subtle.deriveKey(
+    { name: "PBKDF2", salt, iterations: 310_000, hash: "SHA-256" },
+    material,
+    { name: "AES-GCM", length: 256 },
+    false,
+    ["encrypt", "decrypt"]
+  );
+}
 
-  const command = new Deno.Command("ffmpeg", {
-    args: ffmpegArgs,
-    stdin: "piped",
-    stdout: "piped",
-    stderr: "piped",
+async function encryptSyncPayload(
+  passphrase: string,
+  payload: z.infer<typeof syncPayloadSchema>
+): Promise<string> {
+  const salt = crypto.getRandomValues(new Uint8Array(16));
+  const iv = crypto.getRandomValues(new Uint8Array(12));
+  const key = await deriveSyncKey(passphrase, salt);
+  const data = await crypto.subtle.encrypt(
+    { name: "AES-GCM", iv },
+    key,
+    new TextEncoder().encode(JSON.stringify(payload))
+  );
+  return JSON.stringify({
+    version: 1,
+    salt: encodeBase64(salt),
+    iv: encodeBase64(iv),
+    data: encodeBase64(new Uint8Array(data)),
   });
+}
 
-  const process = command.spawn();
+async function decryptSyncPayload(
+  passphrase: string,
+  file: string
+): Promise<z.infer<typeof syncPayloadSchema>> {
+  const { salt, iv, data } = JSON.parse(file);
+  const key = await deriveSyncKey(passphrase, decodeBase64(salt));
+  const plain = await crypto.subtle.decrypt(
+    { name: "AES-GCM", iv: decodeBase64(iv) },
+    key,
+    decodeBase64(data)
+  );
+  return syncPayloadSchema.parse(
+    JSON.parse(new TextDecoder().decode(plain))
+  );
+}
 
-  // Log and save the ffmpeg process PID for the second process to kill
-  logToFile("INFO", `FFmpeg process PID: ${process.pid}`);
-  await setState("ffmpegPid", process.pid.toString());
+async function syncHistory(): Promise<void> {
+  const config = await loadSettings();
+  if (!config.syncDir || !config.syncPassphrase) {
+    console.error("❌ Set syncDir and syncPassphrase to enable sync");
+    Deno.exit(1);
+  }
 
-  await playBeep(); // System bell for start
-  await notify("🎙️ Recording started", "low");
-  await logToFile("INFO", "🎙️  Recording started...");
+  const history = new Map(
+    (await loadHistory()).map((entry) => [entry.id, entry])
+  );
+  const modes = { ...config.modes };
+  const ownFile = join(config.syncDir, `${Deno.hostname()}.typr-sync`);
 
-  const startTimestamp = Date.now();
+  await Deno.mkdir(config.syncDir, { recursive: true });
+  for await (const file of Deno.readDir(config.syncDir)) {
+    const path = join(config.syncDir, file.name);
+    if (!file.name.endsWith(".typr-sync") || path === ownFile) continue;
+    try {
+      const remote = await decryptSyncPayload(
+        config.syncPassphrase,
+        await Deno.readTextFile(path)
+      );
+      for (const entry of remote.history) {
+        if (!history.has(entry.id)) history.set(entry.id, entry);
+      }
+      // Locally defined modes win over remote ones with the same name
+      for (const [name, mode] of Object.entries(remote.modes)) {
+        modes[name] ??= mode;
+      }
+      console.log(`⬇️  Merged ${file.name}`);
+    } catch (error) {
+      console.error(`❌ Could not read ${file.name} (wrong passphrase?)`);
+      await logToFile("ERROR", `Failed to decrypt ${path}`, error);
+    }
+  }
 
-  // Capture stderr for debugging
-  const stderr = await new Response(process.stderr).text();
+  await saveHistory([...history.values()], config.historyLimit);
+  await saveSettings({ ...config, modes });
 
-  // Wait for the ffmpeg process to finish (either killed by second process or naturally)
-  const status = await process.status;
-  await logToFile("INFO", `FFmpeg process finished with code: ${status.code}`);
+  const merged = { history: await loadHistory(), modes };
+  await Deno.writeTextFile(
+    ownFile,
+    await encryptSyncPayload(config.syncPassphrase, merged)
+  );
+  console.log(
+    `✅ Synced ${merged.history.length} history entries and ${
+      Object.keys(modes).length
+    } modes`
+  );
+}
 
-  if (stderr.trim()) {
-    await logToFile("INFO", `FFmpeg stderr: ${stderr}`);
+// Per-stage latency metrics (milliseconds), kept for the last runs only
+const METRICS_LIMIT = 100;
+
+const metricsRunSchema = z.object({
+  timestamp: z.string(),
+  provider: z.string(),
+  llmProvider: z.string(),
+  stages: z.record(z.number()),
+});
+
+type MetricsRun = z.infer<typeof metricsRunSchema>;
+
+async function loadMetrics(): Promise<MetricsRun[]> {
+  try {
+    const data = JSON.parse(await Deno.readTextFile(METRICS_FILE));
+    return z.array(metricsRunSchema).parse(data);
+  } catch {
+    return [];
   }
+}
 
-  // If it's been less than 1 second, then just exit
-  if (Date.now() - startTimestamp < 1000) {
-    await logToFile("INFO", "Recording stopped after less than 1 second");
-    await notify("⏹️ Recording stopped after less than 1 second", "low");
-    return null;
+async function recordMetrics(run: MetricsRun): Promise<void> {
+  const runs = [...(await loadMetrics()), run].slice(-METRICS_LIMIT);
+  await Deno.writeTextFile(METRICS_FILE, JSON.stringify(runs, null, 2));
+}
+
+async function showDiagnostics(): Promise<void> {
+  const runs = await loadMetrics();
+  if (runs.length === 0) {
+    console.log("No dictations recorded yet");
+    return;
+  }
+
+  const average = (values: number[]) =>
+    Math.round(values.reduce((sum, value) => sum + value, 0) / values.length);
+
+  console.log(`📊 Average stage latency over the last ${runs.length} runs`);
+  const stages = [...new Set(runs.flatMap((run) => Object.keys(run.stages)))];
+  for (const stage of stages) {
+    const values = runs
+      .map((run) => run.stages[stage])
+      .filter((value) => value !== undefined);
+    console.log(`  ${stage.padEnd(12)} ${average(values)} ms`);
+  }
+
+  console.log("\n🔌 Average transcription latency by provider");
+  for (const provider of new Set(runs.map((run) => run.provider))) {
+    const values = runs
+      .filter((run) => run.provider === provider)
+      .map((run) => run.stages.transcribe ?? 0);
+    console.log(
+      `  ${provider.padEnd(12)} ${average(values)} ms (${values.length} runs)`
+    );
   }
 
-  return audioPath;
+  const last = runs[runs.length - 1];
+  console.log(`\n🕑 Last run (${last.timestamp})`);
+  for (const [stage, duration] of Object.entries(last.stages)) {
+    console.log(`  ${stage.padEnd(12)} ${duration} ms`);
+  }
 }
 
-// Transcription functions
-async function transcribeWithLocalWhisper(
-  audioPath: string,
-  whisperPrompt: string
-): Promise<string> {
-  await logToFile(
-    "INFO",
-    `🔄 Using local Whisper for transcription... ${audioPath}`
+// API key rotation and usage tracking
+const keyUsageSchema = z.object({
+  requests: z.number().default(0),
+  failures: z.number().default(0),
+  rateLimited: z.number().default(0),
+  lastUsed: z.string().default(""),
+});
+
+const usageSchema = z.object({
+  nextKey: z.number().default(0),
+  // Keyed by keyId, so keys sharing their last characters stay apart
+  keys: z.record(keyUsageSchema).default({}),
+});
+
+type Usage = z.infer<typeof usageSchema>;
+
+// Failures the user can tell apart by ear
+type FailureKind = "no-api-key" | "network" | "no-transcript";
+
+class TyprFailure extends Error {
+  constructor(readonly kind: FailureKind, message: string) {
+    super(message);
+  }
+}
+
+class ProviderError extends Error {
+  constructor(
+    readonly provider: string,
+    readonly status: number,
+    body: string,
+    // Seconds the provider asked us to wait before retrying (HTTP 429)
+    readonly retryAfter: number | null = null
+  ) {
+    super(`${provider} API error (${status}): ${body}`);
+  }
+}
+
+class OpenAIError extends ProviderError {
+  constructor(status: number, body: string, retryAfter: number | null = null) {
+    super("OpenAI", status, body, retryAfter);
+  }
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+function retryAfterSeconds(response: Response): number | null {
+  const header = response.headers.get("retry-after");
+  if (!header) return null;
+  const seconds = Number(header);
+  if (!Number.isNaN(seconds)) return Math.max(0, Math.ceil(seconds));
+  const date = Date.parse(header);
+  return Number.isNaN(date)
+    ? null
+    : Math.max(0, Math.ceil((date - Date.now()) / 1000));
+}
+
+// Wait out rate limits instead of failing the dictation, as long as the
+// provider's requested delay is within rateLimitMaxWaitSeconds
+async function withRateLimitRetry<T>(
+  config: Settings,
+  request: () => Promise<T>
+): Promise<T> {
+  for (let attempt = 1; ; attempt++) {
+    try {
+      return await request();
+    } catch (error) {
+      if (!(error instanceof ProviderError) || error.status !== 429) {
+        throw error;
+      }
+      const wait = error.retryAfter ?? 5 * attempt;
+      if (attempt >= 3 || wait > config.rateLimitMaxWaitSeconds) throw error;
+
+      await logToFile(
+        "INFO",
+        `${error.provider} rate limited, retrying in ${wait}s`
+      );
+      await notify(`⏳ Rate limited, retrying in ${wait}s`, "low");
+      await new Promise((resolve) => setTimeout(resolve, wait * 1000));
+    }
+  }
+}
+
+function getOpenAIKeys(config: Settings): string[] {
+  if (!hasFeature("openai")) return [];
+  const keys = [config.openAIKey, ...config.openAIKeys]
+    .map((key) => key.trim())
+    .filter((key) => key.length > 0);
+  return [...new Set(keys)];
+}
+
+// Never store or log full keys, only enough to tell them apart
+function keyLabel(apiKey: string): string {
+  return `…${apiKey.slice(-4)}`;
+}
+
+// A short hash of the whole key, for storing usage
+async function keyId(apiKey: string): Promise<string> {
+  const digest = await crypto.subtle.digest(
+    "SHA-256",
+    new TextEncoder().encode(apiKey)
   );
+  return Array.from(new Uint8Array(digest).slice(0, 6), (byte) =>
+    byte.toString(16).padStart(2, "0")
+  ).join("");
+}
 
-  const args = [
-    audioPath,
-    "--model",
-    "base",
-    "--language",
-    "en",
-    "--output_format",
-    "txt",
-    "--output_dir",
-    "/tmp",
-    "--verbose",
-    "False",
-  ];
+async function loadUsage(): Promise<Usage> {
+  try {
+    return usageSchema.parse(JSON.parse(await Deno.readTextFile(USAGE_FILE)));
+  } catch {
+    return usageSchema.parse({});
+  }
+}
 
-  if (whisperPrompt.trim()) {
-    args.push("--initial_prompt", whisperPrompt);
+async function saveUsage(usage: Usage): Promise<void> {
+  await Deno.writeTextFile(USAGE_FILE, JSON.stringify(usage, null, 2));
+}
+
+async function recordKeyUsage(
+  apiKey: string,
+  outcome: "success" | "failure" | "rateLimited"
+): Promise<void> {
+  const usage = await loadUsage();
+  const id = await keyId(apiKey);
+  const entry = keyUsageSchema.parse(usage.keys[id] ?? {});
+  entry.requests += 1;
+  if (outcome === "failure") entry.failures += 1;
+  if (outcome === "rateLimited") entry.rateLimited += 1;
+  entry.lastUsed = new Date().toISOString();
+  usage.keys[id] = entry;
+  await saveUsage(usage);
+}
+
+// Run a request against each configured key until one is accepted
+async function withOpenAIKey<T>(
+  config: Settings,
+  request: (apiKey: string) => Promise<T>
+): Promise<T> {
+  const keys = getOpenAIKeys(config);
+  if (keys.length === 0) {
+    throw new TyprFailure("no-api-key", "No OpenAI API key configured");
   }
 
-  const command = new Deno.Command("whisper", { args });
-  const process = command.spawn();
-  const status = await process.status;
+  let ordered = keys;
+  if (config.keyRotation === "round-robin") {
+    const usage = await loadUsage();
+    const start = usage.nextKey % keys.length;
+    ordered = [...keys.slice(start), ...keys.slice(0, start)];
+    usage.nextKey = (start + 1) % keys.length;
+    await saveUsage(usage);
+  }
 
-  if (!status.success) {
-    throw new Error("Whisper transcription failed");
+  // Only once every key is rate limited is it worth waiting
+  return await withRateLimitRetry(config, async () => {
+    let lastError: unknown;
+    for (const apiKey of ordered) {
+      try {
+        const result = await request(apiKey);
+        await recordKeyUsage(apiKey, "success");
+        return result;
+      } catch (error) {
+        lastError = error;
+        // Rate limits, exhausted quota and revoked keys move on to the next key
+        if (error instanceof OpenAIError && [401, 429].includes(error.status)) {
+          await recordKeyUsage(
+            apiKey,
+            error.status === 429 ? "rateLimited" : "failure"
+          );
+          await logToFile(
+            "ERROR",
+            `Key ${keyLabel(apiKey)} rejected, trying next key`,
+            error
+          );
+          continue;
+        }
+        await recordKeyUsage(apiKey, "failure");
+        throw error;
+      }
+    }
+    throw lastError;
+  });
+}
+
+// Azure OpenAI uses per-deployment URLs and an `api-key` header instead of
+// the public API's model field and bearer token
+type OpenAIEndpoint = { url: string; headers: Record<string, string> };
+
+function openAIEndpoint(
+  config: Settings,
+  path: "audio/transcriptions" | "chat/completions",
+  apiKey: string
+): OpenAIEndpoint {
+  if (!config.azureEndpoint) {
+    return {
+      url: `https://api.openai.com/v1/${path}`,
+      headers: { Authorization: `Bearer ${apiKey}` },
+    };
   }
 
-  // Read the output file
-  const baseName =
-    audioPath
-      .split("/")
-      .pop()
-      ?.replace(/\.[^/.]+$/, "") || "recording";
-  const outputFile = `/tmp/${baseName}.txt`;
+  const deployment =
+    path === "audio/transcriptions"
+      ? config.azureTranscriptionDeployment
+      : config.azureChatDeployment;
+  const base = config.azureEndpoint.replace(/\/+$/, "");
+  return {
+    url: `${base}/openai/deployments/${deployment}/${path}?api-version=${config.azureApiVersion}`,
+    headers: { "api-key": apiKey },
+  };
+}
 
+// Notification functions
+async function notify(
+  message: string,
+  urgency: "low" | "normal" | "critical" = "normal"
+): Promise<void> {
   try {
-    const content = await Deno.readTextFile(outputFile);
-    await Deno.remove(outputFile); // Clean up
-    return content.trim();
+    if (Deno.build.os === "darwin") {
+      // Use macOS native notifications
+      const script = `display notification "${message.replace(
+        /"/g,
+        '\\"'
+      )}" with title "Typr"`;
+      const command = new Deno.Command("osascript", { args: ["-e", script] });
+      await command.output();
+    } else {
+      // Use desktop notifications for Linux
+      const command = new Deno.Command("notify-send", {
+        args: ["--urgency", urgency, "--app-name", "Typr", message],
+      });
+      await command.output();
+    }
   } catch (error) {
-    throw new Error(`Failed to read transcription output: ${error}`);
+    // Fallback to console if notifications fail
+    console.log(`🔔 ${message}`);
+    await logToFile("ERROR", "Failed to send notification", error);
   }
 }
 
-async function transcribeWithOpenAI(
-  audioPath: string,
-  apiKey: string,
-  whisperPrompt: string
-): Promise<string> {
-  await logToFile("INFO", "🔄 Using OpenAI API for transcription...");
+// Transcript event stream
+type TranscriptEvent = {
+  type: "transcript";
+  final: boolean;
+  text: string;
+  timestamp: string;
+};
 
-  const audioData = await Deno.readFile(audioPath);
+type PhaseEvent = {
+  type: "phase";
+  phase: Phase;
+  timestamp: string;
+};
 
-  const formData = new FormData();
-  formData.append("file", new Blob([audioData]), "audio.wav");
-  formData.append("model", "whisper-1");
-  formData.append("response_format", "text");
-  formData.append("language", "en");
-  formData.append("temperature", "0.2");
+type PipelineEvent = TranscriptEvent | PhaseEvent;
 
-  if (whisperPrompt.trim()) {
-    formData.append("prompt", whisperPrompt + "\n\nTranscription:");
+// Hand an event to the `typr serve` hub, which fans it out to subscribers
+async function publishEvent(
+  config: Settings,
+  event: PipelineEvent
+): Promise<void> {
+  if (!config.eventStreamEnabled) return;
+  if (event.type === "transcript" && !event.final) {
+    if (!config.eventStreamPartials) return;
+  }
+
+  try {
+    const response = await fetch(
+      `http://127.0.0.1:${config.eventStreamPort}/publish`,
+      {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(event),
+        signal: AbortSignal.timeout(1000),
+      }
+    );
+    await response.body?.cancel();
+  } catch (error) {
+    // The hub is optional - dictation must keep working without it
+    await logToFile("DEBUG", "Event stream hub not reachable", error);
+  }
+}
+
+async function runServer(): Promise<void> {
+  const config = await loadSettings();
+  const sockets = new Set<WebSocket>();
+
+  // The remote microphone is the only feature meant to be reachable from
+  // the LAN, and it requires a shared token
+  const remoteMic = config.remoteMicEnabled && config.remoteMicToken !== "";
+  if (config.remoteMicEnabled && !remoteMic) {
+    console.error("❌ Set remoteMicToken to enable the remote microphone");
   }
 
-  const response = await fetch(
-    "https://api.openai.com/v1/audio/transcriptions",
+  // The port doubles as the ownership check: a second `typr serve` bows out
+  // instead of fighting the first one
+  try {
+    Deno.listen({ port: config.eventStreamPort }).close();
+  } catch (error) {
+    if (!(error instanceof Deno.errors.AddrInUse)) throw error;
+    console.error(
+      `❌ Port ${config.eventStreamPort} is taken - is typr serve already running?`
+    );
+    Deno.exit(1);
+  }
+
+  // Remote dictations go through /tmp too
+  scheduleCleanup(config);
+
+  Deno.serve(
     {
-      method: "POST",
-      headers: {
-        Authorization: `Bearer ${apiKey}`,
+      hostname: remoteMic ? "0.0.0.0" : "127.0.0.1",
+      port: config.eventStreamPort,
+      onListen: ({ port }) => {
+        console.log(
+          `📡 Streaming transcripts on ws://127.0.0.1:${port}/events`
+        );
+        if (remoteMic) {
+          console.log(
+            `📱 Remote microphone on http://<this-machine>:${port}/mic?token=${config.remoteMicToken}`
+          );
+        }
       },
-      body: formData,
+    },
+    async (request, info) => {
+      const url = new URL(request.url);
+      const isLocal = ["127.0.0.1", "::1"].includes(info.remoteAddr.hostname);
+
+      if (remoteMic && ["/mic", "/audio"].includes(url.pathname)) {
+        if (url.searchParams.get("token") !== config.remoteMicToken) {
+          return new Response("Unauthorized", { status: 401 });
+        }
+        if (url.pathname === "/mic") {
+          return new Response(remoteMicPage(config.remoteMicToken), {
+            headers: { "Content-Type": "text/html; charset=utf-8" },
+          });
+        }
+        if (request.method === "POST") {
+          return await handleRemoteAudio(request);
+        }
+      }
+
+      if (!isLocal) {
+        return new Response("Forbidden", { status: 403 });
+      }
+
+      if (url.pathname === "/events") {
+        if (request.headers.get("upgrade") !== "websocket") {
+          return new Response("Expected a WebSocket upgrade", { status: 426 });
+        }
+        const { socket, response } = Deno.upgradeWebSocket(request);
+        socket.onopen = () => sockets.add(socket);
+        socket.onclose = () => sockets.delete(socket);
+        socket.onerror = () => sockets.delete(socket);
+        return response;
+      }
+
+      if (url.pathname === "/publish" && request.method === "POST") {
+        const message = await request.text();
+        for (const socket of sockets) {
+          if (socket.readyState === WebSocket.OPEN) {
+            socket.send(message);
+          }
+        }
+        return new Response(null, { status: 204 });
+      }
+
+      return new Response("Not found", { status: 404 });
     }
   );
 
-  const transcription = await response.text();
+  await logToFile("INFO", `Server listening on port ${config.eventStreamPort}`);
+}
 
-  await logToFile("INFO", `OpenAI transcription: ${transcription}`);
+// A file input with `capture` works over plain HTTP on phones, unlike
+// getUserMedia which needs a secure context
+function remoteMicPage(token: string): string {
+  return `<!doctype html>
+<html>
+<head>
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>Typr</title>
+</head>
+<body style="font-family: sans-serif; text-align: center; padding: 2em">
+  <h1>🎙️ Typr</h1>
+  <input id="audio" type="file" accept="audio/*" capture>
+  <p id="status"></p>
+  <script>
+    const input = document.getElementById("audio");
+    const status = document.getElementById("status");
+    input.onchange = async () => {
+      status.textContent = "Transcribing...";
+      const response = await fetch("/audio?token=${encodeURIComponent(token)}", {
+        method: "POST",
+        body: input.files[0],
+      });
+      const result = await response.json();
+      status.textContent = response.ok ? result.text : result.error;
+      input.value = "";
+    };
+  </script>
+</body>
+</html>`;
+}
 
-  if (!response.ok) {
-    throw new Error(`OpenAI API error: ${transcription}`);
+// Audio feedback functions
+async function playBeep(): Promise<void> {
+  try {
+    // Use system bell - simple, universal, and reliable
+    const command = new Deno.Command("printf", { args: ["\\a"] });
+    await command.output();
+  } catch (error) {
+    // Silent fallback - don't let beep failures break recording
+    await logToFile("ERROR", "Failed to play system bell", error);
   }
+}
 
-  return transcription;
+async function playDoubleBeep(): Promise<void> {
+  await playBeep();
+  // Short pause between beeps
+  await new Promise((resolve) => setTimeout(resolve, 150));
+  await playBeep();
 }
 
-// Text processing with OpenAI
-async function processWithGPT(
-  transcription: string,
-  apiKey: string,
-  llmPrompt: string
-): Promise<string> {
-  await logToFile("INFO", "🤖 Processing with GPT-4o-mini...");
+// [frequency Hz, duration s] per tone
+const FAILURE_CUES: Record<FailureKind, [number, number][]> = {
+  "no-api-key": [
+    [330, 0.15],
+    [330, 0.15],
+    [330, 0.15],
+  ], // Low triple: go fix settings
+  network: [
+    [880, 0.12],
+    [440, 0.25],
+  ], // Falling: try again shortly
+  "no-transcript": [[660, 0.3]], // Single: just re-dictate
+};
 
-  const response = await fetch("https://api.openai.com/v1/chat/completions", {
-    method: "POST",
-    headers: {
-      Authorization: `Bearer ${apiKey}`,
-      "Content-Type": "application/json",
-    },
-    body: JSON.stringify({
-      model: "gpt-4o-mini",
-      messages: [
-        {
-          role: "user",
-          content: `Task: ${llmPrompt}\n\nTranscription: ${transcription}`,
-        },
-      ],
-      temperature: 0.2,
-    }),
+function classifyFailure(error: unknown): FailureKind {
+  if (error instanceof TyprFailure) return error.kind;
+  if (error instanceof OpenAIError && error.status === 401) return "no-api-key";
+  return "network";
+}
+
+// Typr's own sounds (tones, read-back) leave their player in the state file,
+// so a recording that starts meanwhile can silence them rather than
+// transcribe them
+async function playSound(name: string, args: string[]): Promise<boolean> {
+  const player = new Deno.Command(name, {
+    args,
+    stdout: "null",
+    stderr: "null",
+  }).spawn();
+  await setState("playbackPid", String(player.pid));
+  await setState("playbackCommand", name);
+  const { success } = await player.status;
+  await setState("playbackPid", "");
+  return success;
+}
+
+async function stopPlayback(): Promise<void> {
+  const pid = Number(await getState("playbackPid"));
+  if (!pid) return;
+  try {
+    Deno.kill(pid, "SIGTERM");
+    await logToFile("INFO", `Stopped Typr's own playback (PID ${pid})`);
+  } catch {
+    // Already finished
+  }
+  // speech-dispatcher keeps talking after its client is gone
+  if ((await getState("playbackCommand")) === "spd-say") {
+    await new Deno.Command("spd-say", { args: ["--cancel"] })
+      .output()
+      .catch(() => {});
+  }
+}
+
+// A finished dictation's sounds would end up in the next one
+async function isMicLive(): Promise<boolean> {
+  const phase = await getPhase();
+  return phase === "starting" || phase === "recording";
+}
+
+async function playTone(frequency: number, duration: number): Promise<void> {
+  // ffplay ships with ffmpeg, which recording already requires
+  const success = await playSound("ffplay", [
+    "-nodisp",
+    "-autoexit",
+    "-loglevel",
+    "quiet",
+    "-f",
+    "lavfi",
+    `sine=frequency=${frequency}:duration=${duration}`,
+  ]);
+  if (!success) throw new Error("ffplay failed");
+}
+
+async function playFailureCue(kind: FailureKind): Promise<void> {
+  if (await isMicLive()) return;
+  try {
+    for (const [frequency, duration] of FAILURE_CUES[kind]) {
+      await playTone(frequency, duration);
+    }
+  } catch (error) {
+    await logToFile("ERROR", "Failed to play failure cue", error);
+    await playDoubleBeep();
+  }
+}
+
+// Audio recording functions
+// Binary name each local Whisper flavor installs by default
+const LOCAL_WHISPER_BINARIES: Record<Settings["localWhisperFlavor"], string> = {
+  "openai-whisper": "whisper",
+  "whisper.cpp": "whisper-cli",
+  whisperx: "whisperx",
+};
+
+function localWhisperBinary(config: Settings): string {
+  return (
+    expandHome(config.localWhisperPath) ||
+    LOCAL_WHISPER_BINARIES[config.localWhisperFlavor]
+  );
+}
+
+async function isWhisperAvailable(config: Settings): Promise<boolean> {
+  if (!hasFeature("local-whisper")) return false;
+  try {
+    const command = new Deno.Command(localWhisperBinary(config), {
+      args: ["--help"],
+      stdout: "null",
+      stderr: "null",
+    });
+    const process = command.spawn();
+    const status = await process.status;
+    return status.success;
+  } catch {
+    return false;
+  }
+}
+
+// With setUp false nothing is loaded, for read-only queries like status
+async function microphoneInputArgs(
+  config?: Settings,
+  setUp = true
+): Promise<string[]> {
+  if (Deno.build.os === "darwin") {
+    if (setUp && config?.echoCancellation) {
+      await logToFile("INFO", "Echo cancellation is only supported on Linux");
+    }
+    return ["-f", "avfoundation", "-i", ":0"]; // macOS microphone
+  }
+
+  const source = !config?.echoCancellation
+    ? "default"
+    : setUp
+    ? (await ensureEchoCancelSource()) ?? "default"
+    : ECHO_CANCEL_SOURCE;
+  return ["-f", "pulse", "-i", source]; // Linux
+}
+
+// PulseAudio/PipeWire's echo-cancel module subtracts what the speakers are
+// playing from the microphone, leaving only the speaker's voice
+const ECHO_CANCEL_SOURCE = "typr_echo_cancel";
+
+async function ensureEchoCancelSource(): Promise<string | null> {
+  try {
+    const list = await new Deno.Command("pactl", {
+      args: ["list", "short", "sources"],
+    }).output();
+    if (new TextDecoder().decode(list.stdout).includes(ECHO_CANCEL_SOURCE)) {
+      return ECHO_CANCEL_SOURCE;
+    }
+
+    const load = await new Deno.Command("pactl", {
+      args: [
+        "load-module",
+        "module-echo-cancel",
+        `source_name=${ECHO_CANCEL_SOURCE}`,
+        "aec_method=webrtc",
+      ],
+    }).output();
+    if (!load.success) throw new Error(new TextDecoder().decode(load.stderr));
+    await logToFile("INFO", "Loaded PulseAudio echo-cancel module");
+    return ECHO_CANCEL_SOURCE;
+  } catch (error) {
+    await logToFile("ERROR", "Echo cancellation unavailable", error);
+    return null;
+  }
+}
+
+// Mute everything quieter than the threshold (keyboard clatter, chatter)
+function noiseGateArgs(config: Settings): string[] {
+  if (config.noiseGateDb >= 0) return [];
+  const threshold = Math.pow(10, config.noiseGateDb / 20);
+  return [
+    "-af",
+    `agate=threshold=${threshold.toFixed(5)}:range=0.001:attack=5:release=200`,
+  ];
+}
+
+// Play the mic into the headphones while recording, as a second ffmpeg
+// output. A PulseAudio sink name or an AudioToolbox device index picks where
+// it goes; the default output otherwise.
+function sidetoneArgs(config: Settings): string[] {
+  if (!config.sidetone) return [];
+  const volume = ["-filter:a", `volume=${config.sidetoneVolume}`];
+  const device = config.sidetoneDevice;
+  switch (Deno.build.os) {
+    case "linux":
+      return [
+        ...volume,
+        "-f",
+        "pulse",
+        // Milliseconds of buffering; more than this and the delay is
+        // distracting
+        "-buffer_duration",
+        "30",
+        ...(device ? ["-device", device] : []),
+        "Typr monitor",
+      ];
+    case "darwin":
+      return [
+        ...volume,
+        "-f",
+        "audiotoolbox",
+        ...(device ? ["-audio_device_index", device] : []),
+        "-",
+      ];
+    default:
+      return [];
+  }
+}
+
+async function measureVolume(
+  path: string
+): Promise<{ mean: number; max: number }> {
+  const command = new Deno.Command("ffmpeg", {
+    args: ["-i", path, "-af", "volumedetect", "-f", "null", "-"],
+    stdout: "null",
+    stderr: "piped",
+  });
+  const { stderr } = await command.output();
+  const output = new TextDecoder().decode(stderr);
+  const read = (name: string) => {
+    const match = output.match(new RegExp(`${name}: (-?[\\d.]+) dB`));
+    return parseFloat(match?.[1] ?? "");
+  };
+  return { mean: read("mean_volume"), max: read("max_volume") };
+}
+
+// Cheap energy-based VAD: seconds of the recording louder than the threshold
+async function measureSpeech(
+  path: string,
+  thresholdDb: number
+): Promise<number> {
+  const command = new Deno.Command("ffmpeg", {
+    args: [
+      "-i",
+      path,
+      "-af",
+      `silencedetect=noise=${thresholdDb}dB:d=0.2`,
+      "-f",
+      "null",
+      "-",
+    ],
+    stdout: "null",
+    stderr: "piped",
+  });
+  const { stderr } = await command.output();
+  const output = new TextDecoder().decode(stderr);
+  const duration = await audioDuration(path);
+
+  const starts = [...output.matchAll(/silence_start: ([\d.]+)/g)];
+  const lengths = [...output.matchAll(/silence_duration: ([\d.]+)/g)];
+  let silent = lengths.reduce(
+    (total, match) => total + parseFloat(match[1]),
+    0
+  );
+
+  // Silence that runs to the end of the file has a start but no duration
+  if (starts.length > lengths.length) {
+    silent += duration - parseFloat(starts[starts.length - 1][1]);
+  }
+  return Math.max(0, duration - silent);
+}
+
+// Fixed-length recording without the toggle machinery (setup, calibration)
+async function recordSample(path: string, seconds: number): Promise<boolean> {
+  const command = new Deno.Command("ffmpeg", {
+    args: [
+      ...(await microphoneInputArgs()),
+      "-t",
+      String(seconds),
+      "-acodec",
+      "pcm_s16le",
+      "-ar",
+      "16000",
+      "-ac",
+      "1",
+      "-y",
+      path,
+    ],
+    stdout: "null",
+    stderr: "null",
+  });
+  const { success } = await command.output();
+  return success && (await Deno.stat(path)).size > 44;
+}
+
+// Upload-while-recording: ffmpeg also writes fixed-length segments, and each
+// finished segment is transcribed while the speaker is still talking
+class StreamingTranscriber {
+  private results: string[] = [];
+  private started = 0;
+  private chain: Promise<void> = Promise.resolve();
+  private failed = false;
+  private timer: number;
+
+  constructor(private config: Settings, private prefix: string) {
+    this.timer = setInterval(() => this.poll(), 1000);
+  }
+
+  static supports(config: Settings): boolean {
+    return (
+      config.streamingChunkSeconds > 0 &&
+      !config.useLocalWhisper &&
+      config.transcriptionProvider !== "wyoming"
+    );
+  }
+
+  get pattern(): string {
+    return `${this.prefix}-%03d.wav`;
+  }
+
+  private segmentPath(index: number): string {
+    return `${this.prefix}-${String(index).padStart(3, "0")}.wav`;
+  }
+
+  // A segment is complete once ffmpeg has moved on to the next one
+  private poll(): void {
+    while (existsSync(this.segmentPath(this.started + 1))) {
+      const index = this.started++;
+      this.chain = this.chain.then(() => this.transcribe(index));
+    }
+  }
+
+  private async transcribe(index: number): Promise<void> {
+    if (this.failed) return;
+    const path = this.segmentPath(index);
+    try {
+      // Carry the previous segment's tail as context across the cut
+      const previous = this.results[index - 1] ?? "";
+      const { text } = await transcribeWithProvider(path, {
+        ...this.config,
+        whisperPrompt: `${this.config.whisperPrompt}\n\n${previous.slice(-200)}`,
+      });
+      this.results[index] = text.trim();
+      await logToFile("INFO", `Streamed segment ${index}: ${text}`);
+    } catch (error) {
+      this.failed = true;
+      await logToFile("ERROR", `Streaming segment ${index} failed`, error);
+    }
+  }
+
+  // Transcribe what's left after recording stopped; null means fall back to
+  // transcribing the full recording
+  async finish(): Promise<string | null> {
+    clearInterval(this.timer);
+    while (existsSync(this.segmentPath(this.started))) {
+      const index = this.started++;
+      this.chain = this.chain.then(() => this.transcribe(index));
+    }
+    await this.chain;
+
+    for (let index = 0; index < this.started; index++) {
+      await Deno.remove(this.segmentPath(index)).catch(() => {});
+    }
+    if (this.failed || this.started === 0) return null;
+    return this.results.join(" ");
+  }
+}
+
+function existsSync(path: string): boolean {
+  try {
+    Deno.statSync(path);
+    return true;
+  } catch {
+    return false;
+  }
+}
+
+// Live dictation over the OpenAI Realtime API: microphone PCM is streamed
+// continuously and each finished utterance arrives (and is typed) within
+// moments of the speaker pausing
+async function runLiveDictation(
+  config: Settings,
+  onText: (text: string) => Promise<void>,
+  onPartial: (text: string) => Promise<void> = async () => {},
+  inputArgs?: string[]
+): Promise<void> {
+  const apiKey = getOpenAIKeys(config)[0];
+  if (!apiKey) {
+    throw new TyprFailure("no-api-key", "Live dictation needs an OpenAI key");
+  }
+
+  const socket = new WebSocket(
+    "wss://api.openai.com/v1/realtime?intent=transcription",
+    [
+      "realtime",
+      `openai-insecure-api-key.${apiKey}`,
+      "openai-beta.realtime-v1",
+    ]
+  );
+  await new Promise<void>((resolve, reject) => {
+    socket.onopen = () => resolve();
+    socket.onerror = () => reject(new Error("Realtime connection failed"));
+  });
+
+  socket.send(
+    JSON.stringify({
+      type: "transcription_session.update",
+      session: {
+        input_audio_format: "pcm16",
+        input_audio_transcription: {
+          model: config.liveModel,
+          prompt: config.whisperPrompt,
+          language: config.language === "auto" ? undefined : config.language,
+        },
+        turn_detection: { type: "server_vad", silence_duration_ms: 500 },
+      },
+    })
+  );
+
+  // Typing is serialised so utterances land in order
+  let pending = 0;
+  let output = Promise.resolve();
+  socket.onmessage = (message) => {
+    const event = JSON.parse(message.data);
+    switch (event.type) {
+      case "input_audio_buffer.committed":
+        pending++;
+        break;
+      case "conversation.item.input_audio_transcription.delta":
+        output = output.then(() => onPartial(event.delta));
+        break;
+      case "conversation.item.input_audio_transcription.completed":
+        pending--;
+        if (event.transcript?.trim()) {
+          output = output.then(() => onText(event.transcript.trim()));
+        }
+        break;
+      case "error":
+        logToFile("ERROR", "Realtime API error", JSON.stringify(event.error));
+        break;
+    }
+  };
+
+  // Realtime audio is 24 kHz mono PCM16
+  const process = new Deno.Command("ffmpeg", {
+    args: [
+      ...(inputArgs ?? (await microphoneInputArgs(config))),
+      ...noiseGateArgs(config),
+      "-f",
+      "s16le",
+      "-ar",
+      "24000",
+      "-ac",
+      "1",
+      "pipe:1",
+    ],
+    stdout: "piped",
+    stderr: "null",
+  }).spawn();
+  await markRecording(process.pid);
+  await playBeep();
+  await notify("🔴 Live dictation started", "low");
+
+  for await (const chunk of process.stdout) {
+    if (socket.readyState !== WebSocket.OPEN) break;
+    socket.send(
+      JSON.stringify({
+        type: "input_audio_buffer.append",
+        audio: encodeBase64(chunk),
+      })
+    );
+  }
+
+  // Flush the last utterance and give its transcript a moment to arrive
+  socket.send(JSON.stringify({ type: "input_audio_buffer.commit" }));
+  const deadline = Date.now() + 10_000;
+  await new Promise((resolve) => setTimeout(resolve, 500));
+  while (pending > 0 && Date.now() < deadline) {
+    await new Promise((resolve) => setTimeout(resolve, 100));
+  }
+  await output;
+  socket.close();
+}
+
+// Hands-free recording that stops itself once the speaker goes quiet
+async function recordUntilSilence(
+  path: string,
+  config: Settings
+): Promise<boolean> {
+  const process = new Deno.Command("ffmpeg", {
+    args: [
+      ...(await microphoneInputArgs(config)),
+      "-af",
+      `silencedetect=noise=-35dB:d=${config.wakeWordSilenceSeconds}`,
+      "-acodec",
+      "pcm_s16le",
+      "-ar",
+      "16000",
+      "-ac",
+      "1",
+      "-t",
+      "120",
+      "-y",
+      path,
+    ],
+    stdout: "null",
+    stderr: "piped",
+  }).spawn();
+
+  // Give up if nobody starts talking within a few seconds
+  let spoke = false;
+  const noSpeech = setTimeout(() => {
+    if (!spoke) process.kill("SIGTERM");
+  }, 8000);
+
+  const lines = process.stderr
+    .pipeThrough(new TextDecoderStream())
+    .pipeThrough(new TextLineStream());
+  for await (const line of lines) {
+    if (line.includes("silence_end")) spoke = true;
+    if (spoke && line.includes("silence_start")) {
+      process.kill("SIGTERM");
+    }
+  }
+  clearTimeout(noSpeech);
+  await process.status;
+  return spoke;
+}
+
+// Captions transcribe `captionSource` (e.g. a PulseAudio monitor or a
+// BlackHole device carrying call audio) rather than the microphone
+function captionInputArgs(source: string): string[] {
+  return Deno.build.os === "darwin"
+    ? ["-f", "avfoundation", "-i", `:${source}`]
+    : ["-f", "pulse", "-i", source];
+}
+
+// Live captions in the terminal: the current utterance is redrawn in place
+// as it streams in, and each finished one scrolls up. Nothing is typed.
+async function runCaptions(): Promise<void> {
+  const config = await loadActiveSettings();
+  const encoder = new TextEncoder();
+  const write = (text: string) => Deno.stdout.write(encoder.encode(text));
+  let partial = "";
+
+  const redraw = async () => {
+    let width = 80;
+    try {
+      width = Deno.consoleSize().columns;
+    } catch {
+      // Not a terminal (piped output)
+    }
+    await write(`\r\x1b[2K\x1b[2m${partial.slice(-(width - 1))}\x1b[0m`);
+  };
+
+  // Ctrl+C also reaches ffmpeg, which ends the stream; stay alive long
+  // enough to caption the last utterance
+  Deno.addSignalListener("SIGINT", () => {});
+
+  await runLiveDictation(
+    config,
+    async (text) => {
+      partial = "";
+      await write(`\r\x1b[2K${text}\n`);
+    },
+    async (delta) => {
+      partial += delta;
+      await redraw();
+    },
+    config.captionSource ? captionInputArgs(config.captionSource) : undefined
+  );
+  await setPhase("idle");
+}
+
+// Always-listening mode: an external wake-word engine (openWakeWord,
+// porcupine, ...) runs as `wakeWordCommand` and exits successfully when it
+// hears the wake word
+async function listenForWakeWord(): Promise<void> {
+  const config = await loadSettings();
+  if (!config.wakeWordCommand) {
+    console.error("❌ Set wakeWordCommand to a wake-word detector first");
+    Deno.exit(1);
+  }
+
+  Deno.addSignalListener("SIGINT", async () => {
+    await notify("🔇 Wake-word listening stopped - mic is off", "low");
+    Deno.exit(0);
+  });
+  scheduleCleanup(config);
+  await notify("👂 Listening for wake word - mic is hot", "normal");
+  console.log("👂 Listening for wake word (Ctrl+C to stop, mic is hot)");
+
+  while (true) {
+    const detector = await new Deno.Command("sh", {
+      args: ["-c", config.wakeWordCommand],
+      stdout: "null",
+    }).output();
+    if (!detector.success) {
+      await logToFile("ERROR", `Wake-word command exited ${detector.code}`);
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+      continue;
+    }
+
+    if (!(await acquireRecordingLock())) {
+      await notify("⏳ Already recording - ignoring the wake word", "low");
+      continue;
+    }
+
+    const focusedWindow = await getFocusedWindow();
+    const stamp = new Date().toISOString().replace(/[:.]/g, "-");
+    const audioPath = `/tmp/typr-recording-${stamp}.wav`;
+    await playBeep();
+    await notify("🎙️ Wake word heard - recording", "low");
+
+    let spoke: boolean;
+    try {
+      spoke = await recordUntilSilence(audioPath, config);
+    } finally {
+      await releaseRecordingLock();
+    }
+    if (!spoke) {
+      await notify("🤷 Didn't hear anything after the wake word", "low");
+      continue;
+    }
+
+    const stoppedAt = Date.now();
+    try {
+      const result = await processAudioFile(audioPath);
+      const settings = await loadActiveSettings();
+      if (!result.handled) {
+        await deliverResult(
+          result.openaiResponse,
+          settings,
+          focusedWindow,
+          result.email
+        );
+      }
+      await recordRunMetrics(result, settings, stoppedAt, audioPath);
+    } catch (error) {
+      await logToFile("ERROR", "Failed to process audio", error);
+      await recordRunFailure(error, stoppedAt, audioPath);
+      await playFailureCue(classifyFailure(error));
+      await notify("❌ Processing failed", "critical");
+    }
+  }
+}
+
+// Recordings, streamed segments, chunks, MP3 uploads and Whisper transcripts
+// are all named typr-*
+async function cleanupRecordings(config: Settings): Promise<void> {
+  if (config.recordingRetentionHours === 0) return;
+  const cutoff = Date.now() - config.recordingRetentionHours * 3600_000;
+
+  const dirs = new Set(["/tmp", config.localWhisperOutputDir]);
+  let removed = 0;
+  for (const dir of dirs) {
+    try {
+      for await (const entry of Deno.readDir(dir)) {
+        if (!entry.isFile || !entry.name.startsWith("typr-")) continue;
+        const path = join(dir, entry.name);
+        const { mtime } = await Deno.stat(path);
+        if (mtime && mtime.getTime() < cutoff) {
+          await Deno.remove(path);
+          removed++;
+        }
+      }
+    } catch (error) {
+      await logToFile("ERROR", `Failed to clean up ${dir}`, error);
+    }
+  }
+  if (removed) {
+    await logToFile("INFO", `Removed ${removed} old recording files`);
+  }
+}
+
+// Long-running commands clean up at startup and then hourly
+function scheduleCleanup(config: Settings): void {
+  cleanupRecordings(config);
+  setInterval(() => cleanupRecordings(config), 3600_000);
+}
+
+// Visible text of the frontmost window, via the accessibility tree (macOS)
+const MAC_WINDOW_TEXT_SCRIPT = `function run() {
+  const events = Application("System Events");
+  const proc = events.processes.whose({ frontmost: true })[0];
+  const texts = [];
+  proc.windows[0].entireContents().forEach((element) => {
+    for (const name of ["AXValue", "AXTitle", "AXDescription"]) {
+      try {
+        const value = element.attributes.byName(name).value();
+        if (typeof value === "string" && value) texts.push(value);
+      } catch (e) {}
+    }
+  });
+  return texts.join("\\n");
+}`;
+
+// Screenshot of the screen, read back with tesseract
+async function ocrScreen(): Promise<string> {
+  const imagePath = `/tmp/typr-screen-${Date.now()}.png`;
+  const capture =
+    Deno.build.os === "darwin"
+      ? new Deno.Command("screencapture", { args: ["-x", imagePath] })
+      : Deno.env.get("WAYLAND_DISPLAY")
+      ? new Deno.Command("grim", { args: [imagePath] })
+      : new Deno.Command("import", { args: ["-window", "root", imagePath] });
+  try {
+    if (!(await capture.output()).success) return "";
+    const { stdout } = await new Deno.Command("tesseract", {
+      args: [imagePath, "stdout"],
+      stderr: "null",
+    }).output();
+    return new TextDecoder().decode(stdout);
+  } finally {
+    await Deno.remove(imagePath).catch(() => {});
+  }
+}
+
+// Names, jargon and identifiers worth priming Whisper with: capitalised or
+// mixed-case words, acronyms and words containing digits
+function extractKeyTerms(text: string, limit = 30): string[] {
+  const counts = new Map<string, number>();
+  for (const [word] of text.matchAll(/\p{L}[\p{L}\p{N}_+#-]*[\p{L}\p{N}]/gu)) {
+    const interesting =
+      (/^\p{Lu}/u.test(word) && word.length >= 3) ||
+      /\p{Ll}\p{Lu}/u.test(word) ||
+      /\d/.test(word);
+    if (!interesting || STOP_WORDS.has(word.toLowerCase())) continue;
+    counts.set(word, (counts.get(word) ?? 0) + 1);
+  }
+  return [...counts.entries()]
+    .sort((a, b) => b[1] - a[1])
+    .slice(0, limit)
+    .map(([word]) => word);
+}
+
+// Sentence-initial words that are capitalised without being names
+// prettier-ignore
+const STOP_WORDS = new Set([
+  "the", "and", "for", "you", "this", "that", "with", "from", "have", "are",
+  "was", "but", "not", "all", "can", "will", "your", "about", "what", "when",
+  "which", "there", "their", "they", "then", "than", "into", "more", "also",
+  "just", "some", "been", "would", "could", "should", "here", "how", "new",
+]);
+
+async function captureScreenTerms(config: Settings): Promise<string[]> {
+  if (config.screenContext === "off") return [];
+  try {
+    let text = "";
+    if (config.screenContext === "accessibility") {
+      // A screenshot captures far more than the window text that was chosen
+      if (Deno.build.os !== "darwin") {
+        await logToFile(
+          "INFO",
+          'Accessibility screen context is macOS-only, use "ocr" on Linux'
+        );
+        return [];
+      }
+      const { stdout } = await new Deno.Command("osascript", {
+        args: ["-l", "JavaScript", "-e", MAC_WINDOW_TEXT_SCRIPT],
+        stderr: "null",
+      }).output();
+      text = new TextDecoder().decode(stdout);
+    } else {
+      text = await ocrScreen();
+    }
+    const terms = extractKeyTerms(text);
+    await logToFile("DEBUG", `Screen terms: ${terms.join(", ")}`);
+    return terms;
+  } catch (error) {
+    await logToFile("ERROR", "Failed to capture screen context", error);
+    return [];
+  }
+}
+
+type Recording = {
+  audioPath: string;
+  streamed: StreamingTranscriber | null;
+};
+
+async function startRecording(config: Settings): Promise<Recording | null> {
+  // Clean up old recordings to prevent /tmp from filling up
+  await cleanupRecordings(config);
+
+  const audioPath = `/tmp/typr-recording-${new Date()
+    .toISOString()
+    .replace(/[:.]/g, "-")}.wav`;
+
+  logToFile("INFO", `Recording to ${audioPath}`);
+  setState("audioPath", audioPath);
+
+  const outputArgs = [
+    ...noiseGateArgs(config),
+    "-acodec",
+    "pcm_s16le",
+    "-ar",
+    "16000",
+    "-ac",
+    "1",
+  ];
+
+  // Use ffmpeg for cross-platform audio recording
+  const ffmpegArgs = [
+    ...(await microphoneInputArgs(config)),
+    ...outputArgs,
+    "-f",
+    "wav", // Explicitly specify WAV format
+    "-y", // Overwrite output file
+    audioPath,
+  ];
+
+  const streamed = StreamingTranscriber.supports(config)
+    ? new StreamingTranscriber(config, audioPath.replace(/\.wav$/, "-segment"))
+    : null;
+  if (streamed) {
+    ffmpegArgs.push(
+      ...outputArgs,
+      "-f",
+      "segment",
+      "-segment_time",
+      String(config.streamingChunkSeconds),
+      "-y",
+      streamed.pattern
+    );
+  }
+  ffmpegArgs.push(...sidetoneArgs(config));
+
+  logToFile("INFO", `ffmpegArgs: ${ffmpegArgs}`);
+
+  const command = new Deno.Command("ffmpeg", {
+    args: ffmpegArgs,
+    stdin: "piped",
+    stdout: "piped",
+    stderr: "piped",
   });
 
-  if (!response.ok) {
-    const errorText = await response.text();
-    throw new Error(`OpenAI API error: ${errorText}`);
+  const process = command.spawn();
+
+  // Log and save the ffmpeg process PID for the second process to kill
+  logToFile("INFO", `FFmpeg process PID: ${process.pid}`);
+  await markRecording(process.pid);
+
+  await playBeep(); // System bell for start
+  // Show the mode so it's always clear which prompt will be applied
+  await notify(
+    config.activeMode === "default"
+      ? "🎙️ Recording started"
+      : `🎙️ Recording started [${config.activeMode}]`,
+    "low"
+  );
+  await logToFile("INFO", "🎙️  Recording started...");
+
+  const startTimestamp = Date.now();
+
+  // Nudge (without stopping) when a dictation runs long
+  const warningTimer =
+    config.recordingWarningSeconds > 0
+      ? setTimeout(async () => {
+          await playTone(1200, 0.05).catch(() => playBeep());
+          await notify(
+            `⏱️ Still recording after ${Math.round(
+              config.recordingWarningSeconds / 60
+            )} min`,
+            "low"
+          );
+        }, config.recordingWarningSeconds * 1000)
+      : undefined;
+
+  // Failsafe for a key release the OS never delivered: stop and keep what
+  // was said rather than recording forever
+  const limitTimer =
+    config.maxRecordingSeconds > 0
+      ? setTimeout(async () => {
+          await logToFile(
+            "INFO",
+            `Recording hit maxRecordingSeconds (${config.maxRecordingSeconds}s)`
+          );
+          await notify(
+            `⏹️ Stopped recording after ${Math.round(
+              config.maxRecordingSeconds / 60
+            )} min - processing what was said`
+          );
+          try {
+            Deno.kill(process.pid, "SIGTERM");
+          } catch {
+            // Already stopped
+          }
+        }, config.maxRecordingSeconds * 1000)
+      : undefined;
+
+  // Capture stderr for debugging
+  const stderr = await new Response(process.stderr).text();
+
+  // Wait for the ffmpeg process to finish (either killed by second process or naturally)
+  const status = await process.status;
+  clearTimeout(warningTimer);
+  clearTimeout(limitTimer);
+  await logToFile("INFO", `FFmpeg process finished with code: ${status.code}`);
+
+  if (stderr.trim()) {
+    await logToFile("INFO", `FFmpeg stderr: ${stderr}`);
+  }
+
+  // If it's been less than 1 second, then just exit
+  if (Date.now() - startTimestamp < 1000) {
+    await logToFile("INFO", "Recording stopped after less than 1 second");
+    await notify("⏹️ Recording stopped after less than 1 second", "low");
+    await streamed?.finish();
+    return null;
+  }
+
+  return { audioPath, streamed };
+}
+
+// Normalise any audio ffmpeg understands to the 16 kHz mono WAV we record
+async function convertToWav(
+  inputPath: string,
+  outputPath: string
+): Promise<void> {
+  const command = new Deno.Command("ffmpeg", {
+    args: [
+      "-i",
+      inputPath,
+      "-vn",
+      "-acodec",
+      "pcm_s16le",
+      "-ar",
+      "16000",
+      "-ac",
+      "1",
+      "-y",
+      outputPath,
+    ],
+    stdout: "null",
+    stderr: "piped",
+  });
+  const { success, stderr } = await command.output();
+  if (!success) {
+    throw new Error(
+      `ffmpeg conversion failed: ${new TextDecoder().decode(stderr)}`
+    );
+  }
+}
+
+// Transcription functions
+type Transcript = {
+  text: string;
+  // Language the provider detected, when it reports one
+  language: string | null;
+  // Mean of Whisper's per-segment avg_logprob, when it reports one
+  avgLogprob?: number | null;
+};
+
+// Command-line arguments for one local Whisper flavor; every flavor writes
+// its transcript to <outputBase>.txt
+function localWhisperArgs(
+  config: Settings,
+  audioPath: string,
+  outputBase: string,
+  whisperPrompt: string,
+  language: string
+): string[] {
+  // Whisper detects the language itself when it isn't given one
+  const detect = language === "auto";
+
+  if (config.localWhisperFlavor === "whisper.cpp") {
+    const args = [
+      "-m",
+      expandHome(config.localWhisperModel),
+      "-f",
+      audioPath,
+      "-otxt",
+      "-of",
+      outputBase,
+      "-np",
+      "-l",
+      language,
+    ];
+    if (whisperPrompt.trim()) {
+      args.push("--prompt", whisperPrompt);
+    }
+    return [...args, ...config.localWhisperArgs];
+  }
+
+  // openai-whisper and whisperx share a command line
+  const args = [
+    audioPath,
+    "--model",
+    config.localWhisperModel,
+    "--output_format",
+    "txt",
+    "--output_dir",
+    config.localWhisperOutputDir,
+    "--verbose",
+    "False",
+  ];
+  if (!detect) {
+    args.push("--language", language);
+  }
+  if (whisperPrompt.trim()) {
+    args.push("--initial_prompt", whisperPrompt);
+  }
+  const terms = vocabularyTerms(config);
+  if (
+    config.localWhisperFlavor === "whisperx" &&
+    config.localWhisperHotwords &&
+    terms.length
+  ) {
+    args.push("--hotwords", terms.join(" "));
+  }
+  return [...args, ...config.localWhisperArgs];
+}
+
+async function transcribeWithLocalWhisper(
+  audioPath: string,
+  config: Settings
+): Promise<string> {
+  await logToFile(
+    "INFO",
+    `🔄 Using local Whisper (${config.localWhisperFlavor}) for transcription... ${audioPath}`
+  );
+
+  const baseName =
+    audioPath
+      .split("/")
+      .pop()
+      ?.replace(/\.[^/.]+$/, "") || "recording";
+  const outputBase = join(config.localWhisperOutputDir, baseName);
+
+  const command = new Deno.Command(localWhisperBinary(config), {
+    args: localWhisperArgs(
+      config,
+      audioPath,
+      outputBase,
+      config.whisperPrompt,
+      config.language
+    ),
+  });
+  const process = command.spawn();
+  const status = await process.status;
+
+  if (!status.success) {
+    throw new Error("Whisper transcription failed");
+  }
+
+  // Read the output file
+  const outputFile = `${outputBase}.txt`;
+
+  try {
+    const content = await Deno.readTextFile(outputFile);
+    await Deno.remove(outputFile); // Clean up
+    return content.trim();
+  } catch (error) {
+    throw new Error(`Failed to read transcription output: ${error}`);
+  }
+}
+
+async function transcribeWithOpenAI(
+  audioPath: string,
+  endpoint: OpenAIEndpoint,
+  model: string,
+  whisperPrompt: string,
+  language: string,
+  withConfidence = false
+): Promise<Transcript> {
+  await logToFile("INFO", `🔄 Transcribing via ${endpoint.url}...`);
+
+  const audioData = await Deno.readFile(audioPath);
+
+  const formData = new FormData();
+  formData.append("file", new Blob([audioData]), `audio${extname(audioPath)}`);
+  formData.append("model", model);
+  formData.append("temperature", "0.2");
+
+  // verbose_json reports which language was detected and per-segment
+  // confidence, but only Whisper models offer it
+  const detect = language === "auto";
+  const verbose = (detect || withConfidence) && !model.startsWith("gpt-4o");
+  formData.append("response_format", verbose ? "verbose_json" : "text");
+  if (!detect) {
+    formData.append("language", language);
+  }
+
+  if (whisperPrompt.trim()) {
+    formData.append("prompt", whisperPrompt + "\n\nTranscription:");
+  }
+
+  const response = await providerFetch(endpoint.url, {
+    method: "POST",
+    headers: endpoint.headers,
+    body: formData,
+  });
+
+  const transcription = await response.text();
+
+  await logToFile("INFO", `OpenAI transcription: ${transcription}`);
+
+  if (!response.ok) {
+    throw new OpenAIError(
+      response.status,
+      transcription,
+      retryAfterSeconds(response)
+    );
+  }
+
+  if (verbose) {
+    const data = JSON.parse(transcription);
+    const logprobs: number[] = (data.segments ?? [])
+      .map((segment: { avg_logprob?: number }) => segment.avg_logprob)
+      .filter(Number.isFinite);
+    return {
+      text: data.text ?? "",
+      language: detect ? data.language ?? null : null,
+      avgLogprob: logprobs.length
+        ? logprobs.reduce((sum, value) => sum + value, 0) / logprobs.length
+        : null,
+    };
+  }
+  return { text: transcription, language: null };
+}
+
+async function transcribeWithElevenLabs(
+  audioPath: string,
+  config: Settings
+): Promise<Transcript> {
+  await logToFile("INFO", "🔄 Using ElevenLabs Scribe for transcription...");
+
+  const audioData = await Deno.readFile(audioPath);
+
+  const formData = new FormData();
+  formData.append("file", new Blob([audioData]), `audio${extname(audioPath)}`);
+  formData.append("model_id", config.elevenLabsModel);
+  formData.append(
+    "tag_audio_events",
+    config.elevenLabsTagAudioEvents ? "true" : "false"
+  );
+
+  // Leaving the language out lets Scribe detect it, which keeps
+  // code-switched dictations intact
+  if (config.elevenLabsLanguage) {
+    formData.append("language_code", config.elevenLabsLanguage);
+  }
+
+  const response = await providerFetch(
+    "https://api.elevenlabs.io/v1/speech-to-text",
+    {
+      method: "POST",
+      headers: { "xi-api-key": config.elevenLabsKey },
+      body: formData,
+    }
+  );
+
+  if (!response.ok) {
+    const errorText = await response.text();
+    throw new ProviderError(
+      "ElevenLabs",
+      response.status,
+      errorText,
+      retryAfterSeconds(response)
+    );
+  }
+
+  const data = await response.json();
+  await logToFile("INFO", `ElevenLabs transcription: ${data.text}`);
+  return {
+    text: (data.text ?? "").trim(),
+    language: data.language_code ?? null,
+  };
+}
+
+// speaches/faster-whisper-server speak the OpenAI API and usually run
+// without authentication
+function selfHostedEndpoint(config: Settings, path: string): OpenAIEndpoint {
+  return {
+    url: `${config.selfHostedURL.replace(/\/+$/, "")}/${path}`,
+    headers: config.selfHostedKey
+      ? { Authorization: `Bearer ${config.selfHostedKey}` }
+      : {},
+  };
+}
+
+// Wyoming protocol (Home Assistant voice): newline-terminated JSON headers,
+// each optionally followed by a JSON data block and a binary payload
+type WyomingEvent = {
+  type: string;
+  data: Record<string, unknown>;
+  payload?: Uint8Array;
+};
+
+// Pull the raw PCM samples and format out of a WAV file
+function parseWav(wav: Uint8Array): {
+  pcm: Uint8Array;
+  rate: number;
+  width: number;
+  channels: number;
+} {
+  const view = new DataView(wav.buffer, wav.byteOffset, wav.byteLength);
+  const text = new TextDecoder();
+  let format = { rate: 16000, width: 2, channels: 1 };
+  let offset = 12;
+
+  while (offset + 8 <= wav.length) {
+    const id = text.decode(wav.subarray(offset, offset + 4));
+    const size = view.getUint32(offset + 4, true);
+    const body = offset + 8;
+
+    if (id === "fmt ") {
+      format = {
+        channels: view.getUint16(body + 2, true),
+        rate: view.getUint32(body + 4, true),
+        width: view.getUint16(body + 14, true) / 8,
+      };
+    } else if (id === "data") {
+      // Interrupted recordings can leave a bogus size, so clamp to the file
+      const end = Math.min(body + size, wav.length);
+      return { pcm: wav.subarray(body, end), ...format };
+    }
+    offset = body + size + (size % 2);
+  }
+
+  throw new Error("No audio data found in WAV file");
+}
+
+async function writeWyomingEvent(
+  conn: Deno.Conn,
+  type: string,
+  data: Record<string, unknown>,
+  payload?: Uint8Array
+): Promise<void> {
+  const encoder = new TextEncoder();
+  const dataBytes = encoder.encode(JSON.stringify(data));
+  const header: Record<string, unknown> = {
+    type,
+    data_length: dataBytes.length,
+  };
+  if (payload) header.payload_length = payload.length;
+
+  const parts = [encoder.encode(JSON.stringify(header) + "\n"), dataBytes];
+  if (payload) parts.push(payload);
+  for (const part of parts) {
+    let written = 0;
+    while (written < part.length) {
+      written += await conn.write(part.subarray(written));
+    }
+  }
+}
+
+class WyomingReader {
+  private buffer = new Uint8Array(0);
+
+  constructor(private conn: Deno.Conn) {}
+
+  private async fill(): Promise<void> {
+    const chunk = new Uint8Array(4096);
+    const read = await this.conn.read(chunk);
+    if (read === null) throw new Error("Wyoming server closed the connection");
+    const merged = new Uint8Array(this.buffer.length + read);
+    merged.set(this.buffer);
+    merged.set(chunk.subarray(0, read), this.buffer.length);
+    this.buffer = merged;
+  }
+
+  private async take(length: number): Promise<Uint8Array> {
+    while (this.buffer.length < length) await this.fill();
+    const taken = this.buffer.slice(0, length);
+    this.buffer = this.buffer.slice(length);
+    return taken;
+  }
+
+  async next(): Promise<WyomingEvent> {
+    let newline = this.buffer.indexOf(10);
+    while (newline < 0) {
+      await this.fill();
+      newline = this.buffer.indexOf(10);
+    }
+    const decoder = new TextDecoder();
+    const header = JSON.parse(decoder.decode(await this.take(newline + 1)));
+
+    let data = header.data ?? {};
+    if (header.data_length) {
+      const extra = await this.take(header.data_length);
+      data = { ...data, ...JSON.parse(decoder.decode(extra)) };
+    }
+    const payload = header.payload_length
+      ? await this.take(header.payload_length)
+      : undefined;
+    return { type: header.type, data, payload };
+  }
+}
+
+async function transcribeWithWyoming(
+  audioPath: string,
+  config: Settings
+): Promise<Transcript> {
+  await logToFile(
+    "INFO",
+    `🔄 Using Wyoming server ${config.wyomingHost}:${config.wyomingPort}...`
+  );
+
+  const { pcm, rate, width, channels } = parseWav(
+    await Deno.readFile(audioPath)
+  );
+  const format = { rate, width, channels };
+
+  const conn = await Deno.connect({
+    hostname: config.wyomingHost,
+    port: config.wyomingPort,
+  });
+  // Don't hang the dictation forever on a stuck server
+  const timeout = setTimeout(() => conn.close(), 60_000);
+
+  try {
+    await writeWyomingEvent(conn, "transcribe", {
+      language: config.wyomingLanguage || undefined,
+    });
+    await writeWyomingEvent(conn, "audio-start", format);
+
+    const chunkBytes = 1024 * width * channels;
+    for (let start = 0; start < pcm.length; start += chunkBytes) {
+      await writeWyomingEvent(
+        conn,
+        "audio-chunk",
+        format,
+        pcm.subarray(start, start + chunkBytes)
+      );
+    }
+    await writeWyomingEvent(conn, "audio-stop", {});
+
+    const reader = new WyomingReader(conn);
+    while (true) {
+      const event = await reader.next();
+      if (event.type === "transcript") {
+        const text = String(event.data.text ?? "").trim();
+        await logToFile("INFO", `Wyoming transcription: ${text}`);
+        return { text, language: config.wyomingLanguage || null };
+      }
+    }
+  } finally {
+    clearTimeout(timeout);
+    try {
+      conn.close();
+    } catch {
+      // Already closed by the timeout
+    }
+  }
+}
+
+// Runs while the user is still talking: open the TLS connections the upload
+// and the LLM call will reuse (fetch pools them per process), and pull the
+// local Whisper model into the page cache
+async function warmUp(config: Settings): Promise<void> {
+  const origins = new Set<string>();
+  const openAIKey = getOpenAIKeys(config)[0] ?? "";
+  // Only providers that are set up, and included in this build
+  const transcription = isTranscriptionConfigured(config)
+    ? config.transcriptionProvider
+    : null;
+  const llm = isLLMConfigured(config) ? config.llmProvider : null;
+  if (transcription === "openai" || llm === "openai") {
+    origins.add(
+      new URL(openAIEndpoint(config, "audio/transcriptions", openAIKey).url)
+        .origin
+    );
+  }
+  if (transcription === "elevenlabs") origins.add("https://api.elevenlabs.io");
+  if (transcription === "selfhosted") {
+    origins.add(new URL(config.selfHostedURL).origin);
+  }
+  if (llm === "openrouter") origins.add("https://openrouter.ai");
+  if (llm === "gemini") {
+    origins.add("https://generativelanguage.googleapis.com");
+  }
+
+  const warmups = [...origins].map(async (origin) => {
+    try {
+      const response = await fetch(origin, {
+        method: "HEAD",
+        signal: AbortSignal.timeout(3000),
+      });
+      await response.body?.cancel();
+    } catch (error) {
+      await logToFile("DEBUG", `Warm-up of ${origin} failed`, error);
+    }
+  });
+
+  if (config.useLocalWhisper) {
+    const model =
+      config.localWhisperFlavor === "whisper.cpp"
+        ? expandHome(config.localWhisperModel)
+        : join(
+            Deno.env.get("HOME") || ".",
+            ".cache",
+            "whisper",
+            `${config.localWhisperModel}.pt`
+          );
+    warmups.push(
+      (async () => {
+        let file: Deno.FsFile | null = null;
+        try {
+          file = await Deno.open(model);
+          const buffer = new Uint8Array(1 << 20);
+          while ((await file.read(buffer)) !== null) {
+            // Reading is the point: the OS keeps the pages cached
+          }
+        } catch {
+          // Not a file we know how to find; Whisper loads it as usual
+        } finally {
+          file?.close();
+        }
+      })()
+    );
+  }
+  await Promise.all(warmups);
+}
+
+async function routeByLength(
+  config: Settings,
+  audioPath: string
+): Promise<Settings> {
+  const seconds = await audioDuration(audioPath);
+  const route: ProviderRoute | undefined = config.providerRoutes.find(
+    (route) => route.maxSeconds === undefined || seconds <= route.maxSeconds
+  );
+  if (!route) return config;
+  await logToFile(
+    "INFO",
+    `${seconds.toFixed(1)}s recording routed to ${route.provider}${
+      route.model ? ` (${route.model})` : ""
+    }`
+  );
+
+  const routed = { ...config };
+  if (route.provider === "local") {
+    routed.useLocalWhisper = true;
+    if (route.model) routed.localWhisperModel = route.model;
+    return routed;
+  }
+  routed.useLocalWhisper = false;
+  routed.transcriptionProvider = route.provider;
+  switch (route.provider) {
+    case "openai":
+      if (route.model) routed.openAITranscriptionModel = route.model;
+      break;
+    case "elevenlabs":
+      if (route.model) routed.elevenLabsModel = route.model;
+      break;
+    case "selfhosted":
+      if (route.model) routed.selfHostedModel = route.model;
+      if (route.url) routed.selfHostedURL = route.url;
+      if (route.key !== undefined) routed.selfHostedKey = route.key;
+      break;
+  }
+  return routed;
+}
+
+function isTranscriptionConfigured(config: Settings): boolean {
+  if (!hasFeature(config.transcriptionProvider)) return false;
+  switch (config.transcriptionProvider) {
+    case "openai":
+      return getOpenAIKeys(config).length > 0;
+    case "elevenlabs":
+      return config.elevenLabsKey.length > 0;
+    case "selfhosted":
+      return config.selfHostedURL.length > 0;
+    case "wyoming":
+      return config.wyomingHost.length > 0;
+  }
+}
+
+// MP3 bitrates for uploads; speech stays perfectly intelligible at 32-64
+// kbps and uploads several times faster than WAV
+const UPLOAD_BITRATES: Record<Settings["uploadQuality"], string | null> = {
+  lossless: null,
+  high: "64k",
+  standard: "48k",
+  low: "32k",
+};
+
+async function encodeForUpload(
+  audioPath: string,
+  config: Settings
+): Promise<string> {
+  const bitrate = UPLOAD_BITRATES[config.uploadQuality];
+  if (!bitrate || !hasFeature("mp3")) return audioPath;
+
+  const mp3Path = audioPath.replace(/\.wav$/, "") + ".mp3";
+  const command = new Deno.Command("ffmpeg", {
+    args: [
+      "-i",
+      audioPath,
+      "-codec:a",
+      "libmp3lame",
+      "-b:a",
+      bitrate,
+      // LAME's fastest reasonable setting; the best-quality search is slow on
+      // older machines and buys nothing at voice bitrates
+      "-compression_level",
+      "7",
+      "-y",
+      mp3Path,
+    ],
+    stdout: "null",
+    stderr: "null",
+  });
+  const { success } = await command.output();
+  if (!success) {
+    await logToFile("ERROR", "MP3 encoding failed, uploading WAV instead");
+    return audioPath;
+  }
+  return mp3Path;
+}
+
+async function transcribeWithProvider(
+  audioPath: string,
+  config: Settings
+): Promise<Transcript> {
+  // Wyoming streams raw PCM, everything else uploads a file
+  const uploadPath =
+    config.transcriptionProvider === "wyoming"
+      ? audioPath
+      : await encodeForUpload(audioPath, config);
+
+  try {
+    switch (config.transcriptionProvider) {
+      case "openai":
+        return await withOpenAIKey(config, (apiKey) =>
+          transcribeWithOpenAI(
+            uploadPath,
+            openAIEndpoint(config, "audio/transcriptions", apiKey),
+            config.openAITranscriptionModel,
+            config.whisperPrompt,
+            config.language,
+            config.minAvgLogprob < 0
+          )
+        );
+      case "elevenlabs":
+        return await withRateLimitRetry(config, () =>
+          transcribeWithElevenLabs(uploadPath, config)
+        );
+      case "selfhosted":
+        return await withRateLimitRetry(config, () =>
+          transcribeWithOpenAI(
+            uploadPath,
+            selfHostedEndpoint(config, "audio/transcriptions"),
+            config.selfHostedModel,
+            config.whisperPrompt,
+            config.language,
+            config.minAvgLogprob < 0
+          )
+        );
+      case "wyoming":
+        return await transcribeWithWyoming(audioPath, config);
+    }
+  } finally {
+    if (uploadPath !== audioPath) {
+      await Deno.remove(uploadPath).catch(() => {});
+    }
+  }
+}
+
+async function audioDuration(path: string): Promise<number> {
+  const { stdout } = await new Deno.Command("ffprobe", {
+    args: [
+      "-v",
+      "error",
+      "-show_entries",
+      "format=duration",
+      "-of",
+      "default=noprint_wrappers=1:nokey=1",
+      path,
+    ],
+    stdout: "piped",
+    stderr: "null",
+  }).output();
+  return parseFloat(new TextDecoder().decode(stdout));
+}
+
+// Run tasks with at most `limit` in flight, keeping results in input order
+async function mapWithConcurrency<T, R>(
+  items: T[],
+  limit: number,
+  task: (item: T, index: number) => Promise<R>
+): Promise<R[]> {
+  const results: R[] = new Array(items.length);
+  let next = 0;
+  const workers = Array.from(
+    { length: Math.min(limit, items.length) },
+    async () => {
+      while (next < items.length) {
+        const index = next++;
+        results[index] = await task(items[index], index);
+      }
+    }
+  );
+  await Promise.all(workers);
+  return results;
+}
+
+// Transcripts of audio already sent, keyed by a hash of the audio and the
+// settings that shape the result, so re-running the same file is free
+const TRANSCRIPT_CACHE_LIMIT = 200;
+
+const transcriptCacheSchema = z.record(
+  z.object({
+    cachedAt: z.string(),
+    text: z.string(),
+    language: z.string().nullable(),
+    avgLogprob: z.number().nullable().optional(),
+  })
+);
+
+type TranscriptCache = z.infer<typeof transcriptCacheSchema>;
+
+async function loadTranscriptCache(): Promise<TranscriptCache> {
+  try {
+    return transcriptCacheSchema.parse(
+      JSON.parse(await Deno.readTextFile(TRANSCRIPT_CACHE_FILE))
+    );
+  } catch {
+    return {};
+  }
+}
+
+async function transcriptCacheKey(
+  audioPath: string,
+  config: Settings
+): Promise<string> {
+  const provider = config.useLocalWhisper
+    ? `local ${config.localWhisperModel}`
+    : {
+        openai: `openai ${config.openAITranscriptionModel}`,
+        elevenlabs: `elevenlabs ${config.elevenLabsModel}`,
+        selfhosted: `${config.selfHostedURL} ${config.selfHostedModel}`,
+        wyoming: `wyoming ${config.wyomingHost}:${config.wyomingPort}`,
+      }[config.transcriptionProvider];
+  const prompt = await crypto.subtle.digest(
+    "SHA-256",
+    new TextEncoder().encode(config.whisperPrompt)
+  );
+  return [
+    await sha256(audioPath),
+    provider,
+    config.language,
+    encodeBase64(new Uint8Array(prompt)).slice(0, 12),
+  ].join("|");
+}
+
+// Entries are kept in last-used order, so trimming drops the stalest
+async function saveTranscriptCache(cache: TranscriptCache): Promise<void> {
+  const entries = Object.entries(cache).slice(-TRANSCRIPT_CACHE_LIMIT);
+  await Deno.writeTextFile(
+    TRANSCRIPT_CACHE_FILE,
+    JSON.stringify(Object.fromEntries(entries), null, 2)
+  );
+}
+
+async function transcribeLongAudio(
+  audioPath: string,
+  config: Settings,
+  force = false
+): Promise<Transcript> {
+  // The cache is a store of transcripts, which history being off rules out
+  if (!config.transcriptCache || config.historyLimit === 0) {
+    return await transcribeInChunks(audioPath, config);
+  }
+
+  const key = await transcriptCacheKey(audioPath, config);
+  const cache = await loadTranscriptCache();
+  const cached = cache[key];
+  // Re-inserting moves the entry to the end
+  delete cache[key];
+  if (cached && !force) {
+    await logToFile("INFO", "Same audio transcribed before, using the cache");
+    cache[key] = cached;
+    await saveTranscriptCache(cache);
+    const { text, language, avgLogprob } = cached;
+    return { text, language, avgLogprob };
+  }
+
+  const result = await transcribeInChunks(audioPath, config);
+  if (result.text.trim()) {
+    cache[key] = {
+      cachedAt: new Date().toISOString(),
+      text: result.text,
+      language: result.language,
+      avgLogprob: result.avgLogprob ?? null,
+    };
+    await saveTranscriptCache(cache);
+  }
+  return result;
+}
+
+// Long recordings are split into chunks that are transcribed in parallel,
+// which keeps each upload under the provider's size limit and cuts the
+// wall-clock time of meeting-length audio
+async function transcribeInChunks(
+  audioPath: string,
+  config: Settings
+): Promise<Transcript> {
+  const chunks = await splitAudio(audioPath, config.longAudioChunkSeconds);
+  if (!chunks) return await transcribeWithProvider(audioPath, config);
+
+  await logToFile(
+    "INFO",
+    `Transcribing ${chunks.length} chunks, ${config.chunkConcurrency} at a time`
+  );
+
+  try {
+    let done = 0;
+    const results = await mapWithConcurrency(
+      chunks,
+      config.chunkConcurrency,
+      async (chunk) => {
+        const result = await transcribeWithProvider(chunk, config);
+        done += 1;
+        await notify(
+          `⏳ Transcribed ${done} of ${chunks.length} parts`,
+          "low"
+        );
+        return result;
+      }
+    );
+    const logprobs = results
+      .map((result) => result.avgLogprob)
+      .filter((value): value is number => typeof value === "number");
+    return {
+      text: results.map((result) => result.text.trim()).join(" "),
+      language: results[0]?.language ?? null,
+      avgLogprob: logprobs.length ? Math.min(...logprobs) : null,
+    };
+  } finally {
+    for (const chunk of chunks) {
+      await Deno.remove(chunk).catch(() => {});
+    }
+  }
+}
+
+// Chunk files of `chunkSeconds` each, or null when the recording is short
+// enough (or chunking is off) to send in one piece
+async function splitAudio(
+  audioPath: string,
+  chunkSeconds: number
+): Promise<string[] | null> {
+  const duration = chunkSeconds ? await audioDuration(audioPath) : NaN;
+  if (!(duration > chunkSeconds)) return null;
+
+  const prefix = audioPath.replace(/\.wav$/, "") + "-chunk";
+  const { success } = await new Deno.Command("ffmpeg", {
+    args: [
+      "-i",
+      audioPath,
+      "-f",
+      "segment",
+      "-segment_time",
+      String(chunkSeconds),
+      "-c",
+      "copy",
+      "-y",
+      `${prefix}-%03d.wav`,
+    ],
+    stdout: "null",
+    stderr: "null",
+  }).output();
+  if (!success) {
+    throw new Error("Failed to split the recording into chunks");
+  }
+
+  const chunkPath = (index: number) =>
+    `${prefix}-${String(index).padStart(3, "0")}.wav`;
+  const chunks: string[] = [];
+  while (existsSync(chunkPath(chunks.length))) {
+    chunks.push(chunkPath(chunks.length));
+  }
+  return chunks;
+}
+
+// Subtitles straight from the provider: OpenAI's whisper-1 and most
+// self-hosted servers answer response_format=srt
+async function fetchSubtitles(
+  audioPath: string,
+  endpoint: OpenAIEndpoint,
+  model: string,
+  config: Settings
+): Promise<string> {
+  const formData = new FormData();
+  const audioData = await Deno.readFile(audioPath);
+  formData.append("file", new Blob([audioData]), `audio${extname(audioPath)}`);
+  formData.append("model", model);
+  formData.append("response_format", "srt");
+  if (config.language !== "auto") formData.append("language", config.language);
+  if (config.whisperPrompt.trim()) {
+    formData.append("prompt", config.whisperPrompt);
+  }
+
+  const response = await providerFetch(endpoint.url, {
+    method: "POST",
+    headers: endpoint.headers,
+    body: formData,
+  });
+  const body = await response.text();
+  if (!response.ok) {
+    throw new OpenAIError(response.status, body, retryAfterSeconds(response));
+  }
+  return body;
+}
+
+function formatSrtTime(seconds: number): string {
+  const ms = Math.round(seconds * 1000);
+  const pad = (value: number, width = 2) => String(value).padStart(width, "0");
+  return `${pad(Math.floor(ms / 3_600_000))}:${pad(
+    Math.floor(ms / 60_000) % 60
+  )}:${pad(Math.floor(ms / 1000) % 60)},${pad(ms % 1000, 3)}`;
+}
+
+// Chunk n's cues start at n * chunkSeconds; renumber them as one file
+function mergeSubtitles(parts: string[], chunkSeconds: number): string {
+  const cues: string[] = [];
+  parts.forEach((srt, index) => {
+    for (const block of srt.trim().split(/\r?\n\s*\r?\n/)) {
+      const lines = block.split(/\r?\n/);
+      const timing = lines.findIndex((line) => line.includes("-->"));
+      if (timing < 0) continue;
+      const shifted = lines[timing].replace(
+        /(\d+):(\d+):(\d+)[,.](\d+)/g,
+        (_, h, m, s, ms) =>
+          formatSrtTime(
+            Number(h) * 3600 +
+              Number(m) * 60 +
+              Number(s) +
+              Number(ms) / 1000 +
+              index * chunkSeconds
+          )
+      );
+      cues.push([shifted, ...lines.slice(timing + 1)].join("\n"));
+    }
+  });
+  return cues.map((cue, index) => `${index + 1}\n${cue}`).join("\n\n") + "\n";
+}
+
+async function transcribeToSubtitles(
+  audioPath: string,
+  config: Settings
+): Promise<string> {
+  const request = (path: string) => {
+    switch (config.transcriptionProvider) {
+      case "openai":
+        return withOpenAIKey(config, (apiKey) =>
+          fetchSubtitles(
+            path,
+            openAIEndpoint(config, "audio/transcriptions", apiKey),
+            // The gpt-4o models only return plain text
+            config.openAITranscriptionModel.startsWith("gpt-4o")
+              ? "whisper-1"
+              : config.openAITranscriptionModel,
+            config
+          )
+        );
+      case "selfhosted":
+        return withRateLimitRetry(config, () =>
+          fetchSubtitles(
+            path,
+            selfHostedEndpoint(config, "audio/transcriptions"),
+            config.selfHostedModel,
+            config
+          )
+        );
+      default:
+        throw new Error(
+          `Subtitles need the openai or selfhosted provider, not ${config.transcriptionProvider}`
+        );
+    }
+  };
+
+  const chunks = await splitAudio(audioPath, config.longAudioChunkSeconds);
+  if (!chunks) return mergeSubtitles([await request(audioPath)], 0);
+  try {
+    const parts = await mapWithConcurrency(
+      chunks,
+      config.chunkConcurrency,
+      request
+    );
+    return mergeSubtitles(parts, config.longAudioChunkSeconds);
+  } finally {
+    for (const chunk of chunks) {
+      await Deno.remove(chunk).catch(() => {});
+    }
+  }
+}
+
+// Whisper wasn't sure: ask OpenAI's stronger model once before accepting
+// the first transcript. Null keeps the first one.
+async function retranscribeLowConfidence(
+  audioPath: string,
+  config: Settings,
+  first: Transcript
+): Promise<Transcript | null> {
+  await logToFile(
+    "INFO",
+    `Low confidence (avg_logprob ${first.avgLogprob?.toFixed(2)}), retrying with ${config.lowConfidenceModel}`
+  );
+  if (getOpenAIKeys(config).length === 0) {
+    await logToFile("INFO", "No OpenAI key for the low-confidence retry");
+    return null;
+  }
+
+  try {
+    const retry = await transcribeLongAudio(audioPath, {
+      ...config,
+      transcriptionProvider: "openai",
+      openAITranscriptionModel: config.lowConfidenceModel,
+      minAvgLogprob: 0,
+    });
+    if (!retry.text.trim()) return null;
+    return { ...retry, language: retry.language ?? first.language };
+  } catch (error) {
+    await logToFile("ERROR", "Low-confidence retry failed", error);
+    return null;
+  }
+}
+
+// Transcribe an existing audio file, or a video's audio track, and print it
+async function transcribeFile(
+  inputPath: string | undefined,
+  srt = false,
+  outputPath?: string,
+  force = false
+): Promise<void> {
+  if (!inputPath) {
+    console.error(
+      "Usage: typr transcribe <file> [--srt] [--output <file>] [--force]"
+    );
+    Deno.exit(1);
+  }
+
+  const config = await loadActiveSettings();
+  const wavPath = `/tmp/typr-transcribe-${Date.now()}.wav`;
+  await convertToWav(inputPath, wavPath);
+  try {
+    const output = srt
+      ? await transcribeToSubtitles(wavPath, config)
+      : (await transcribeLongAudio(wavPath, config, force)).text + "\n";
+    if (outputPath) {
+      await Deno.writeTextFile(outputPath, output);
+      console.log(`✅ Wrote ${outputPath}`);
+    } else {
+      await Deno.stdout.write(new TextEncoder().encode(output));
+    }
+  } finally {
+    await Deno.remove(wavPath).catch(() => {});
+  }
+}
+
+// Voice memo import: every audio file in importDir is transcribed once. Files
+// are recognised by content hash, so renames, moves and a sync tool
+// re-downloading everything don't import anything twice.
+const MEMO_EXTENSIONS = [
+  ".m4a",
+  ".mp3",
+  ".wav",
+  ".ogg",
+  ".opus",
+  ".aac",
+  ".flac",
+  ".webm",
+  ".amr",
+  ".3gp",
+];
+
+// Sync tools write files gradually; leave anything this fresh for next time
+const MEMO_SETTLE_MS = 30_000;
+
+const importedSchema = z.record(
+  z.object({ file: z.string(), importedAt: z.string() })
+);
+
+type Imported = z.infer<typeof importedSchema>;
+
+async function loadImported(): Promise<Imported> {
+  try {
+    return importedSchema.parse(
+      JSON.parse(await Deno.readTextFile(IMPORTED_FILE))
+    );
+  } catch {
+    return {};
+  }
+}
+
+async function sha256(path: string): Promise<string> {
+  const digest = await crypto.subtle.digest(
+    "SHA-256",
+    await Deno.readFile(path)
+  );
+  return Array.from(new Uint8Array(digest), (byte) =>
+    byte.toString(16).padStart(2, "0")
+  ).join("");
+}
+
+// Returns how many memos were imported
+async function importMemos(dir: string): Promise<number> {
+  const config = await loadActiveSettings();
+  const imported = await loadImported();
+  // Memos older than everything in a full history would be trimmed right
+  // away, so they aren't worth paying to transcribe
+  const history = await loadHistory();
+  const oldestKept =
+    history.length >= config.historyLimit
+      ? history.map((entry) => entry.timestamp).sort()[0]
+      : undefined;
+  let count = 0;
+
+  for await (const entry of Deno.readDir(dir)) {
+    const path = join(dir, entry.name);
+    if (
+      !entry.isFile ||
+      !MEMO_EXTENSIONS.includes(extname(entry.name).toLowerCase())
+    ) {
+      continue;
+    }
+    const info = await Deno.stat(path);
+    if (Date.now() - (info.mtime?.getTime() ?? 0) < MEMO_SETTLE_MS) continue;
+    const hash = await sha256(path);
+    if (imported[hash]) continue;
+    // The memo's own date, not the time it happened to sync
+    const recorded = (info.birthtime ?? info.mtime ?? new Date()).toISOString();
+    if (oldestKept && recorded < oldestKept) {
+      console.log(
+        `⏭️  ${entry.name} is older than the last ${config.historyLimit} dictations (raise historyLimit to import it)`
+      );
+      continue;
+    }
+
+    console.log(`🔄 ${entry.name}`);
+    const wavPath = `/tmp/typr-import-${Date.now()}.wav`;
+    try {
+      await convertToWav(path, wavPath);
+      const { text: transcription } = await transcribeLongAudio(
+        wavPath,
+        config
+      );
+      const text = filterProfanity(formatOutput(transcription, config), config);
+      const kept = await addHistoryEntry(
+        config,
+        transcription,
+        text,
+        "skipped",
+        1,
+        { source: entry.name, timestamp: recorded }
+      );
+      if (!kept) {
+        throw new Error("history didn't keep the transcript (historyLimit)");
+      }
+      imported[hash] = {
+        file: entry.name,
+        importedAt: new Date().toISOString(),
+      };
+      // Saved after every memo so an interrupted import resumes cleanly
+      await Deno.writeTextFile(
+        IMPORTED_FILE,
+        JSON.stringify(imported, null, 2)
+      );
+      console.log(`   ${text}`);
+      count += 1;
+    } catch (error) {
+      await logToFile("ERROR", `Failed to import ${path}`, error);
+      console.error(`❌ ${entry.name}: ${error}`);
+    } finally {
+      await Deno.remove(wavPath).catch(() => {});
+    }
+  }
+  return count;
+}
+
+async function importCommand(
+  dirArg: string | undefined,
+  watch: boolean
+): Promise<void> {
+  const config = await loadSettings();
+  const dir = expandHome(dirArg ?? config.importDir);
+  if (!dir) {
+    console.error("Usage: typr import [folder] [--watch] (or set importDir)");
+    Deno.exit(1);
+  }
+  // Imported memos only live in history
+  if (config.historyLimit === 0) {
+    console.error("❌ History is off (historyLimit is 0), nothing to import to");
+    Deno.exit(1);
+  }
+
+  for (;;) {
+    const count = await importMemos(dir);
+    if (count > 0) {
+      await notify(`🎙️ Imported ${count} voice memo(s) into history`, "low");
+    }
+    if (!watch) {
+      console.log(`✅ Imported ${count} new memo(s) from ${dir}`);
+      return;
+    }
+    await new Promise((resolve) => setTimeout(resolve, 60_000));
+  }
+}
+
+// Wyoming servers answer a `describe` event with their capabilities
+async function checkWyoming(
+  config: Settings
+): Promise<{ ok: boolean; detail: string }> {
+  const address = `${config.wyomingHost}:${config.wyomingPort}`;
+  try {
+    const conn = await Deno.connect({
+      hostname: config.wyomingHost,
+      port: config.wyomingPort,
+    });
+    const timeout = setTimeout(() => conn.close(), 5000);
+    try {
+      await writeWyomingEvent(conn, "describe", {});
+      const event = await new WyomingReader(conn).next();
+      return {
+        ok: event.type === "info",
+        detail: `${address} sent ${event.type}`,
+      };
+    } finally {
+      clearTimeout(timeout);
+      try {
+        conn.close();
+      } catch {
+        // Already closed by the timeout
+      }
+    }
+  } catch (error) {
+    return { ok: false, detail: `${address} unreachable: ${error}` };
+  }
+}
+
+// Cheap authenticated request that proves the provider is reachable
+async function checkTranscriptionProvider(
+  config: Settings
+): Promise<{ ok: boolean; detail: string }> {
+  if (!hasFeature(config.transcriptionProvider)) {
+    return { ok: false, detail: "not included in this build of Typr" };
+  }
+  let endpoint: OpenAIEndpoint;
+  switch (config.transcriptionProvider) {
+    case "openai":
+      endpoint = {
+        url: "https://api.openai.com/v1/models",
+        headers: { Authorization: `Bearer ${getOpenAIKeys(config)[0] ?? ""}` },
+      };
+      break;
+    case "elevenlabs":
+      endpoint = {
+        url: "https://api.elevenlabs.io/v1/models",
+        headers: { "xi-api-key": config.elevenLabsKey },
+      };
+      break;
+    case "selfhosted":
+      endpoint = selfHostedEndpoint(config, "models");
+      break;
+    case "wyoming":
+      return await checkWyoming(config);
+  }
+
+  try {
+    const response = await fetch(endpoint.url, {
+      headers: endpoint.headers,
+      signal: AbortSignal.timeout(5000),
+    });
+    await response.body?.cancel();
+    return {
+      ok: response.ok,
+      detail: `${endpoint.url} responded with ${response.status}`,
+    };
+  } catch (error) {
+    return { ok: false, detail: `${endpoint.url} unreachable: ${error}` };
+  }
+}
+
+// Text processing with an OpenAI-compatible chat completions API
+async function processWithChatCompletion(
+  transcription: string,
+  endpoint: OpenAIEndpoint,
+  model: string,
+  llmPrompt: string
+): Promise<string> {
+  await logToFile("INFO", `🤖 Processing with ${model}...`);
+
+  const response = await providerFetch(endpoint.url, {
+    method: "POST",
+    headers: {
+      ...endpoint.headers,
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({
+      model,
+      messages: [
+        {
+          role: "user",
+          content: `Task: ${llmPrompt}\n\nTranscription: ${transcription}`,
+        },
+      ],
+      temperature: 0.2,
+    }),
+  });
+
+  if (!response.ok) {
+    const errorText = await response.text();
+    throw new OpenAIError(
+      response.status,
+      errorText,
+      retryAfterSeconds(response)
+    );
+  }
+
+  const data = await response.json();
+  return data.choices[0]?.message?.content?.trim() || transcription;
+}
+
+// Gemini has its own request and response shape
+async function processWithGemini(
+  transcription: string,
+  apiKey: string,
+  model: string,
+  llmPrompt: string
+): Promise<string> {
+  await logToFile("INFO", `🤖 Processing with ${model}...`);
+
+  const response = await providerFetch(
+    `https://generativelanguage.googleapis.com/v1beta/models/${model}:generateContent`,
+    {
+      method: "POST",
+      headers: {
+        "x-goog-api-key": apiKey,
+        "Content-Type": "application/json",
+      },
+      body: JSON.stringify({
+        contents: [
+          {
+            role: "user",
+            parts: [
+              { text: `Task: ${llmPrompt}\n\nTranscription: ${transcription}` },
+            ],
+          },
+        ],
+        generationConfig: { temperature: 0.2 },
+      }),
+    }
+  );
+
+  if (!response.ok) {
+    const errorText = await response.text();
+    throw new ProviderError(
+      "Gemini",
+      response.status,
+      errorText,
+      retryAfterSeconds(response)
+    );
+  }
+
+  const data = await response.json();
+  const parts: { text?: string }[] =
+    data.candidates?.[0]?.content?.parts ?? [];
+  return (
+    parts
+      .map((part) => part.text ?? "")
+      .join("")
+      .trim() || transcription
+  );
+}
+
+function isLLMConfigured(config: Settings): boolean {
+  if (!hasFeature(config.llmProvider)) return false;
+  switch (config.llmProvider) {
+    case "openai":
+      return getOpenAIKeys(config).length > 0;
+    case "openrouter":
+      return config.openRouterKey.length > 0;
+    case "gemini":
+      return config.geminiKey.length > 0;
+  }
+}
+
+async function processWithLLM(
+  transcription: string,
+  config: Settings
+): Promise<string> {
+  switch (config.llmProvider) {
+    case "openai":
+      return await withOpenAIKey(config, (apiKey) =>
+        processWithChatCompletion(
+          transcription,
+          openAIEndpoint(config, "chat/completions", apiKey),
+          config.openAIModel,
+          config.llmPrompt
+        )
+      );
+    case "openrouter":
+      return await withRateLimitRetry(config, () =>
+        processWithChatCompletion(
+          transcription,
+          {
+            url: "https://openrouter.ai/api/v1/chat/completions",
+            headers: {
+              Authorization: `Bearer ${config.openRouterKey}`,
+              "X-Title": "Typr",
+            },
+          },
+          config.openRouterModel,
+          config.llmPrompt
+        )
+      );
+    case "gemini":
+      return await withRateLimitRetry(config, () =>
+        processWithGemini(
+          transcription,
+          config.geminiKey,
+          config.geminiModel,
+          config.llmPrompt
+        )
+      );
+  }
+}
+
+// Chat models sometimes wrap the edit in meta-text ("Here is the edited
+// version:") or answer the dictation instead of editing it
+const LLM_PREAMBLE =
+  /^(?:sure|certainly|of course|okay|ok|here(?:'s| is| are)|below is)\b[^\n:]{0,80}\b(?:edit|revis|correct|version|text|transcri|format|clean)[^\n:]{0,40}:\s*\n*/i;
+const LLM_SIGN_OFF =
+  /\n+(?:let me know|i hope this|feel free|if you(?:'d| would) like)[^\n]*$/i;
+
+function cleanLLMResponse(response: string): string {
+  let text = response
+    .trim()
+    .replace(/^```\w*\s*|\s*```$/g, "")
+    .replace(LLM_PREAMBLE, "")
+    .replace(LLM_SIGN_OFF, "")
+    .trim();
+  const quoted = text.match(/^["“]([^"“”]*)["”]$/);
+  if (quoted) text = quoted[1].trim();
+  return text;
+}
+
+function wordsOf(text: string): string[] {
+  return text.toLowerCase().match(/[\p{L}\p{N}']+/gu) ?? [];
+}
+
+// Why an edit is too far from the transcript to trust, or null if it looks
+// like an edit. Notes to the editor can shorten or reword a dictation, so
+// only wild changes in length or wording are rejected
+function llmResponseProblem(
+  response: string,
+  transcription: string
+): string | null {
+  if (!response) return "empty response";
+  const original = wordsOf(transcription);
+  const edited = wordsOf(response);
+  if (original.length < 5) return null;
+
+  const ratio = response.length / transcription.length;
+  if (ratio < 0.3 || ratio > 3) {
+    return `length changed from ${transcription.length} to ${response.length} characters`;
+  }
+  const known = new Set(original);
+  const kept = edited.filter((word) => known.has(word)).length;
+  if (edited.length > 0 && kept / edited.length < 0.5) {
+    return `only ${Math.round((kept / edited.length) * 100)}% of the words are from the transcript`;
+  }
+  return null;
+}
+
+// Clipboard helpers
+// nspasteboard.org markers tell clipboard managers (Maccy, Alfred, Paste,
+// ...) to skip an item instead of adding it to their history
+const MAC_TRANSIENT_COPY_SCRIPT = `ObjC.import("AppKit");
+function run(argv) {
+  const pasteboard = $.NSPasteboard.generalPasteboard;
+  pasteboard.clearContents;
+  pasteboard.setStringForType($(argv[0]), $.NSPasteboardTypeString);
+  pasteboard.setStringForType($(""), "org.nspasteboard.TransientType");
+  pasteboard.setStringForType($(""), "org.nspasteboard.ConcealedType");
+}`;
+
+async function copyToClipboard(
+  text: string,
+  transient = false
+): Promise<void> {
+  if (transient && Deno.build.os === "darwin") {
+    const { success } = await new Deno.Command("osascript", {
+      args: ["-l", "JavaScript", "-e", MAC_TRANSIENT_COPY_SCRIPT, text],
+      stdout: "null",
+    }).output();
+    if (success) return;
+    await logToFile("ERROR", "Transient copy failed, using pbcopy");
+  }
+
+  const command =
+    Deno.build.os === "darwin"
+      ? new Deno.Command("pbcopy", { stdin: "piped" })
+      : Deno.env.get("WAYLAND_DISPLAY")
+      ? new Deno.Command("wl-copy", { stdin: "piped" })
+      : new Deno.Command("xclip", {
+          args: ["-selection", "clipboard"],
+          stdin: "piped",
+        });
+  const process = command.spawn();
+  const writer = process.stdin.getWriter();
+  await writer.write(new TextEncoder().encode(text));
+  await writer.close();
+  await process.status;
+}
+
+async function readClipboard(): Promise<string> {
+  const command =
+    Deno.build.os === "darwin"
+      ? new Deno.Command("pbpaste")
+      : Deno.env.get("WAYLAND_DISPLAY")
+      ? new Deno.Command("wl-paste", { args: ["--no-newline"] })
+      : new Deno.Command("xclip", { args: ["-selection", "clipboard", "-o"] });
+  const { stdout, success } = await command.output();
+  if (!success) throw new Error("Failed to read clipboard");
+  return new TextDecoder().decode(stdout);
+}
+
+// macOS records the owner of secure keyboard entry (password fields,
+// some terminals and password managers) in the console session info
+async function isSecureInputActive(): Promise<boolean> {
+  if (Deno.build.os !== "darwin") return false;
+  try {
+    const command = new Deno.Command("ioreg", { args: ["-l", "-w", "0"] });
+    const { stdout } = await command.output();
+    return new TextDecoder()
+      .decode(stdout)
+      .includes("kCGSSessionSecureInputPID");
+  } catch (error) {
+    await logToFile("ERROR", "Failed to check secure input state", error);
+    return false;
+  }
+}
+
+// Focused window tracking
+type FocusedWindow = {
+  id: string;
+  app: string;
+  title: string;
+  // macOS: role and position of the focused text field ("AXTextArea|12,340")
+  element?: string;
+};
+
+async function getFocusedWindow(): Promise<FocusedWindow | null> {
+  try {
+    if (Deno.build.os === "darwin") {
+      const script = `tell application "System Events"
+  set frontApp to first application process whose frontmost is true
+  set winName to ""
+  try
+    set winName to name of front window of frontApp
+  end try
+  set focusedElement to ""
+  try
+    set el to value of attribute "AXFocusedUIElement" of frontApp
+    set {x, y} to position of el
+    set focusedElement to (role of el) & "|" & x & "," & y
+  end try
+  return (unix id of frontApp as text) & linefeed & name of frontApp & linefeed & winName & linefeed & focusedElement
+end tell`;
+      const command = new Deno.Command("osascript", { args: ["-e", script] });
+      const { stdout, success } = await command.output();
+      if (!success) return null;
+      const [id, app, title = "", element = ""] = new TextDecoder()
+        .decode(stdout)
+        .trim()
+        .split("\n");
+      return { id, app, title, element: element || undefined };
+    }
+
+    const run = async (args: string[]) => {
+      const command = new Deno.Command("xdotool", { args });
+      const { stdout, success } = await command.output();
+      return success ? new TextDecoder().decode(stdout).trim() : "";
+    };
+    const id = await run(["getactivewindow"]);
+    if (!id) return null;
+    return {
+      id,
+      app: await run(["getwindowclassname", id]),
+      title: await run(["getwindowname", id]),
+    };
+  } catch (error) {
+    await logToFile("ERROR", "Failed to get focused window", error);
+    return null;
+  }
+}
+
+// Bring the window back and, on macOS, the text field that had focus; a
+// menu or tray click can leave the window frontmost but the field unfocused
+function macRefocusScript(window: FocusedWindow): string {
+  const [role = "", position = ""] = (window.element ?? "").split("|");
+  const restoreElement = window.element
+    ? `
+  try
+    set current to value of attribute "AXFocusedUIElement" of p
+    set {x, y} to position of current
+    if ((role of current) & "|" & x & "," & y) is "${window.element}" then return
+  end try
+  with timeout of 2 seconds
+    repeat with candidate in (entire contents of front window of p)
+      try
+        if role of candidate is "${role}" then
+          set {x, y} to position of candidate
+          if ((x as text) & "," & (y as text)) is "${position}" then
+            set value of attribute "AXFocused" of candidate to true
+            exit repeat
+          end if
+        end if
+      end try
+    end repeat
+  end timeout`
+    : "";
+  return `tell application "System Events"
+  set p to first process whose unix id is ${window.id}
+  set frontmost of p to true${restoreElement}
+end tell`;
+}
+
+async function refocusWindow(window: FocusedWindow): Promise<void> {
+  const command =
+    Deno.build.os === "darwin"
+      ? new Deno.Command("osascript", {
+          args: ["-e", macRefocusScript(window)],
+        })
+      : new Deno.Command("xdotool", {
+          args: ["windowactivate", "--sync", window.id],
+        });
+  await command.output();
+}
+
+function isTyprWindow(window: FocusedWindow, config: Settings): boolean {
+  const files = [
+    ".typr-",
+    "typr-correct-",
+    "typr-prompt-",
+    basename(expandHome(config.scratchpadFile)),
+    basename(expandHome(config.vocabularyFile)),
+  ].filter((name) => name.length > 0);
+  return files.some((name) => window.title.includes(name));
+}
+
+// Decide whether it's safe to type into whatever has focus now
+async function ensureFocus(
+  original: FocusedWindow | null,
+  config: Settings
+): Promise<boolean> {
+  if (!original || config.focusChangeBehavior === "ignore") return true;
+
+  const current = await getFocusedWindow();
+  if (current?.id === original.id) {
+    // Same app, but the field may have lost focus to a menu
+    if (
+      config.focusChangeBehavior === "refocus" &&
+      original.element &&
+      current.element !== original.element
+    ) {
+      await logToFile("INFO", `Restoring focus to ${original.element}`);
+      await refocusWindow(original);
+    }
+    return true;
+  }
+
+  await logToFile(
+    "INFO",
+    `Focus changed from ${original.app} to ${current?.app ?? "unknown"}`
+  );
+
+  switch (config.focusChangeBehavior) {
+    case "refocus": {
+      await refocusWindow(original);
+      const refocused = await getFocusedWindow();
+      return refocused?.id === original.id;
+    }
+    case "warn":
+      await notify(`⚠️ Focus moved away from ${original.app}`);
+      return true;
+    case "hold":
+      return false;
+  }
+}
+
+// Spoken key commands ("press enter", "select all")
+type OutputSegment = { text: string } | { keys: string };
+
+const SPOKEN_KEYS: Record<string, string> = {
+  enter: "enter",
+  return: "enter",
+  tab: "tab",
+  escape: "escape",
+  backspace: "backspace",
+  delete: "delete",
+  space: "space",
+  up: "up",
+  down: "down",
+  left: "left",
+  right: "right",
+};
+
+const KEY_COMMAND_PATTERN = new RegExp(
+  `\\s*\\b(?:press (${Object.keys(SPOKEN_KEYS).join(
+    "|"
+  )})(?: arrow)?|(select all))\\b[.,!?]?\\s*`,
+  "gi"
+);
+
+function splitKeyCommands(text: string): OutputSegment[] {
+  const segments: OutputSegment[] = [];
+  let last = 0;
+  for (const match of text.matchAll(KEY_COMMAND_PATTERN)) {
+    if (match.index! > last) {
+      segments.push({ text: text.slice(last, match.index) });
+    }
+    segments.push({
+      keys: match[1] ? SPOKEN_KEYS[match[1].toLowerCase()] : "mod+a",
+    });
+    last = match.index! + match[0].length;
+  }
+  if (last < text.length) segments.push({ text: text.slice(last) });
+  return segments;
+}
+
+// Key combos are written like "enter" or "mod+shift+a"; "mod" is Cmd on
+// macOS and Ctrl elsewhere
+// prettier-ignore
+const MAC_KEY_CODES: Record<string, number> = {
+  a: 0, s: 1, d: 2, f: 3, h: 4, g: 5, z: 6, x: 7, c: 8, v: 9, b: 11, q: 12,
+  w: 13, e: 14, r: 15, y: 16, t: 17, o: 31, u: 32, i: 34, p: 35, l: 37,
+  j: 38, k: 40, n: 45, m: 46,
+  enter: 36, tab: 48, space: 49, backspace: 51, escape: 53, delete: 117,
+  left: 123, right: 124, down: 125, up: 126,
+};
+
+const XDOTOOL_KEYS: Record<string, string> = {
+  enter: "Return",
+  tab: "Tab",
+  space: "space",
+  backspace: "BackSpace",
+  escape: "Escape",
+  delete: "Delete",
+  left: "Left",
+  right: "Right",
+  down: "Down",
+  up: "Up",
+};
+
+const MODIFIERS = ["mod", "cmd", "ctrl", "alt", "shift"];
+
+function parseKeyCombo(combo: string): { key: string; modifiers: string[] } {
+  const parts = combo.toLowerCase().split("+").map((part) => part.trim());
+  const key = parts.pop() ?? "";
+  const unknown = parts.find((part) => !MODIFIERS.includes(part));
+  if (unknown) throw new Error(`Unknown modifier "${unknown}" in "${combo}"`);
+  if (!(key in MAC_KEY_CODES)) {
+    throw new Error(`Unknown key "${key}" in "${combo}"`);
+  }
+  return { key, modifiers: parts };
+}
+
+async function pressKeys(combo: string): Promise<void> {
+  const { key, modifiers } = parseKeyCombo(combo);
+
+  if (Deno.build.os === "darwin") {
+    const using = modifiers.map((modifier) =>
+      modifier === "mod" || modifier === "cmd"
+        ? "command down"
+        : modifier === "ctrl"
+        ? "control down"
+        : modifier === "alt"
+        ? "option down"
+        : "shift down"
+    );
+    const script = `tell application "System Events" to key code ${
+      MAC_KEY_CODES[key]
+    }${using.length ? ` using {${using.join(", ")}}` : ""}`;
+    await new Deno.Command("osascript", { args: ["-e", script] }).output();
+    return;
+  }
+
+  const xdotoolCombo = [
+    ...modifiers.map((modifier) =>
+      modifier === "mod" ? "ctrl" : modifier === "cmd" ? "super" : modifier
+    ),
+    XDOTOOL_KEYS[key] ?? key,
+  ].join("+");
+  await new Deno.Command("xdotool", {
+    args: ["key", "--clearmodifiers", xdotoolCombo],
+  }).output();
+}
+
+// Keys to fire after typing, e.g. Enter to send in chat apps. Per-app
+// settings (by app name or window class) win over the mode's
+function postTypeKeysFor(config: Settings, app: string | undefined): string[] {
+  if (app) {
+    const match = Object.entries(config.appPostTypeKeys).find(
+      ([name]) => name.toLowerCase() === app.toLowerCase()
+    );
+    if (match) return match[1];
+  }
+  return config.postTypeKeys;
+}
+
+// The text of a result with its spoken key commands dropped
+function withoutKeyCommands(text: string, config: Settings): string {
+  if (!config.keyCommands) return text;
+  return splitKeyCommands(text)
+    .map((segment) => ("text" in segment ? segment.text : ""))
+    .join(" ");
+}
+
+// A bad combo shouldn't fail a dictation whose text is already typed
+async function pressPostTypeKeys(combos: string[]): Promise<void> {
+  for (const combo of combos) {
+    try {
+      await pressKeys(combo);
+    } catch (error) {
+      await logToFile("ERROR", `Failed to press ${combo}`, error);
+    }
+  }
+}
+
+// Text typing simulation. Returns false when the text went to the clipboard
+// instead, so callers don't press keys into the wrong field
+async function typeText(text: string, config: Settings): Promise<boolean> {
+  await logToFile("INFO", `⌨️  Typing: ${text}`);
+
+  // Test backend: record what would have been typed instead of typing it
+  const captureFile = Deno.env.get("TYPR_CAPTURE_FILE");
+  if (captureFile) {
+    await Deno.writeTextFile(captureFile, text, { append: true });
+    return true;
+  }
+
+  const segments: OutputSegment[] = config.keyCommands
+    ? splitKeyCommands(text)
+    : [{ text }];
+
+  // Keystrokes are swallowed (or land in a password field) while secure
+  // input is on, so hand the text over via the clipboard instead
+  if (await isSecureInputActive()) {
+    await logToFile("INFO", "Secure input active, copying instead of typing");
+    await copyToClipboard(withoutKeyCommands(text, config));
+    // Nothing was typed, so `typr undo` must not take back an older dictation
+    await recordInjection("");
+    await notify("🔒 Secure input active - result copied to clipboard");
+    return false;
+  }
+
+  // Only the text typed after the last key command can be backspaced away
+  let undoable = "";
+  for (const segment of segments) {
+    if ("keys" in segment) {
+      try {
+        await pressKeys(segment.keys);
+      } catch (error) {
+        await logToFile("ERROR", `Failed to press ${segment.keys}`, error);
+      }
+      undoable = "";
+    } else {
+      await injectText(segment.text, config);
+      undoable += segment.text;
+    }
+  }
+  await recordInjection(undoable);
+  return true;
+}
+
+// What the last dictation typed, so `typr undo` can take it back
+const injectionSchema = z.object({
+  text: z.string(),
+  windowId: z.string().nullable(),
+  timestamp: z.string(),
+});
+
+async function recordInjection(text: string): Promise<void> {
+  const window = await getFocusedWindow();
+  await Deno.writeTextFile(
+    INJECTION_FILE,
+    JSON.stringify({
+      text,
+      windowId: window?.id ?? null,
+      timestamp: new Date().toISOString(),
+    })
+  );
+}
+
+async function deleteBackward(count: number, config: Settings): Promise<void> {
+  if (Deno.build.os === "darwin") {
+    const script = `tell application "System Events"
+  repeat ${count} times
+    key code 51
+  end repeat
+end tell`;
+    await new Deno.Command("osascript", { args: ["-e", script] }).output();
+    return;
+  }
+
+  if (resolveLinuxBackend(config) === "wtype") {
+    const args = Array.from({ length: count }, () => ["-k", "BackSpace"]);
+    await new Deno.Command("wtype", { args: args.flat() }).output();
+    return;
+  }
+
+  await new Deno.Command("xdotool", {
+    args: [
+      "key",
+      "--clearmodifiers",
+      "--repeat",
+      String(count),
+      "--delay",
+      String(config.xdotoolDelay),
+      "BackSpace",
+    ],
+  }).output();
+}
+
+// Remove the last dictation from the app it was typed into, independent of
+// the app's own undo stack
+async function undoLastDictation(): Promise<void> {
+  if (!(await exists(INJECTION_FILE))) {
+    await notify("↩️ Nothing to undo", "low");
+    return;
+  }
+  const injection = injectionSchema.parse(
+    JSON.parse(await Deno.readTextFile(INJECTION_FILE))
+  );
+  if (!injection.text) {
+    await notify("↩️ Nothing to undo", "low");
+    return;
+  }
+
+  const window = await getFocusedWindow();
+  if (injection.windowId && window && window.id !== injection.windowId) {
+    await notify("↩️ Switch back to the window you dictated into to undo");
+    return;
+  }
+
+  // Backspace removes one user-perceived character at a time
+  const count = [
+    ...new Intl.Segmenter().segment(injection.text.replace(/\r\n/g, "\n")),
+  ].length;
+  await deleteBackward(count, await loadSettings());
+  await Deno.remove(INJECTION_FILE);
+  await logToFile("INFO", `Undid last dictation (${count} characters)`);
+}
+
+async function injectText(text: string, config: Settings): Promise<void> {
+  // Long dictations are pasted: keystroke typing takes ages for them
+  if (config.pasteThreshold > 0 && text.length > config.pasteThreshold) {
+    try {
+      await pasteText(text, config);
+      return;
+    } catch (error) {
+      await logToFile("ERROR", "Paste failed, falling back to typing", error);
+    }
+  }
+
+  try {
+    switch (Deno.build.os) {
+      case "darwin":
+        await typeMacOS(text, config.macTypingBackend);
+        break;
+      case "linux":
+        await typeLinux(text, config);
+        break;
+      default:
+        throw new Error(
+          `Unsupported OS: ${Deno.build.os}. Only Linux and macOS are supported.`
+        );
+    }
+  } catch (error) {
+    await logToFile("ERROR", "Failed to type text", error);
+  }
+}
+
+async function typeMacOS(
+  text: string,
+  backend: Settings["macTypingBackend"]
+): Promise<void> {
+  if (backend === "cgevent") {
+    try {
+      await typeMacOSCGEvent(text);
+      return;
+    } catch (error) {
+      await logToFile(
+        "ERROR",
+        "CGEvent typing failed, falling back to AppleScript",
+        error
+      );
+    }
+  }
+
+  const script = `tell application "System Events" to keystroke "${text.replace(
+    /"/g,
+    '\\"'
+  )}"`;
+  const command = new Deno.Command("osascript", { args: ["-e", script] });
+  await command.output();
+}
+
+// Direct Quartz event injection via FFI: no escaping, unicode-safe and far
+// faster than a keystroke per character through AppleScript
+const MAC_KEY_RETURN = 36;
+const MAC_KEY_TAB = 48;
+
+const CG_SYMBOLS = {
+  CGEventCreateKeyboardEvent: {
+    parameters: ["pointer", "u16", "bool"],
+    result: "pointer",
+  },
+  CGEventKeyboardSetUnicodeString: {
+    parameters: ["pointer", "usize", "buffer"],
+    result: "void",
+  },
+  CGEventPost: { parameters: ["u32", "pointer"], result: "void" },
+} as const;
+
+const CF_SYMBOLS = {
+  CFRelease: { parameters: ["pointer"], result: "void" },
+} as const;
+
+let quartz:
+  | {
+      cg: Deno.DynamicLibrary<typeof CG_SYMBOLS>;
+      cf: Deno.DynamicLibrary<typeof CF_SYMBOLS>;
+    }
+  | undefined;
+
+function loadQuartz(): NonNullable<typeof quartz> {
+  quartz ??= {
+    cg: Deno.dlopen(
+      "/System/Library/Frameworks/CoreGraphics.framework/CoreGraphics",
+      CG_SYMBOLS
+    ),
+    cf: Deno.dlopen(
+      "/System/Library/Frameworks/CoreFoundation.framework/CoreFoundation",
+      CF_SYMBOLS
+    ),
+  };
+  return quartz;
+}
+
+function postMacKeyEvent(keyCode: number, unicode?: Uint16Array): void {
+  const { cg, cf } = loadQuartz();
+  for (const keyDown of [true, false]) {
+    const event = cg.symbols.CGEventCreateKeyboardEvent(
+      null,
+      keyCode,
+      keyDown
+    );
+    if (!event) throw new Error("CGEventCreateKeyboardEvent failed");
+    if (unicode) {
+      cg.symbols.CGEventKeyboardSetUnicodeString(
+        event,
+        BigInt(unicode.length),
+        unicode
+      );
+    }
+    cg.symbols.CGEventPost(0, event); // kCGHIDEventTap
+    cf.symbols.CFRelease(event);
+  }
+}
+
+async function typeMacOSCGEvent(text: string): Promise<void> {
+  // Events carry at most 20 UTF-16 units; never split a surrogate pair
+  const flush = async (units: number[]) => {
+    if (units.length === 0) return;
+    postMacKeyEvent(0, new Uint16Array(units));
+    units.length = 0;
+    await new Promise((resolve) => setTimeout(resolve, 2));
+  };
+
+  const pending: number[] = [];
+  for (const char of text) {
+    if (char === "\n" || char === "\t") {
+      await flush(pending);
+      postMacKeyEvent(char === "\n" ? MAC_KEY_RETURN : MAC_KEY_TAB);
+      continue;
+    }
+    const units = Array.from({ length: char.length }, (_, i) =>
+      char.charCodeAt(i)
+    );
+    if (pending.length + units.length > 20) await flush(pending);
+    pending.push(...units);
+  }
+  await flush(pending);
+}
+
+async function detectKeyboardLayout(): Promise<string> {
+  try {
+    const command = new Deno.Command("setxkbmap", { args: ["-query"] });
+    const { stdout } = await command.output();
+    const match = new TextDecoder().decode(stdout).match(/^layout:\s*(\S+)/m);
+    if (match) return match[1].split(",")[0];
+  } catch {
+    // setxkbmap missing (e.g. pure Wayland session)
+  }
+  return Deno.env.get("XKB_DEFAULT_LAYOUT")?.split(",")[0] ?? "us";
+}
+
+function resolveLinuxBackend(config: Settings): "xdotool" | "wtype" {
+  if (config.linuxTypingBackend !== "auto") return config.linuxTypingBackend;
+  return Deno.env.get("WAYLAND_DISPLAY") ? "wtype" : "xdotool";
+}
+
+async function typeLinux(text: string, config: Settings): Promise<void> {
+  const backend = resolveLinuxBackend(config);
+
+  // wtype sends unicode through a virtual keyboard, independent of layout
+  if (backend === "wtype") {
+    const { success } = await new Deno.Command("wtype", {
+      args: ["--", text],
+    }).output();
+    if (!success) throw new Error("wtype failed");
+    return;
+  }
+
+  // xdotool maps characters through the active layout; held modifiers and
+  // fast typing are what garble output on non-US layouts
+  const layout = await detectKeyboardLayout();
+  const delay =
+    layout === "us" ? config.xdotoolDelay : Math.max(config.xdotoolDelay, 25);
+  await logToFile("INFO", `Typing with xdotool (layout ${layout})`);
+  const command = new Deno.Command("xdotool", {
+    args: ["type", "--clearmodifiers", "--delay", String(delay), "--", text],
+  });
+  await command.output();
+}
+
+// Paste through the clipboard, putting back whatever was there before
+async function pasteText(text: string, config: Settings): Promise<void> {
+  const transient = config.transientClipboard;
+  let previous: string | null = null;
+  try {
+    previous = await readClipboard();
+  } catch {
+    // Empty or non-text clipboard, nothing to restore
+  }
+
+  await copyToClipboard(text, transient);
+  // Under Wayland xdotool can succeed without the paste reaching the window
+  const command =
+    Deno.build.os === "darwin"
+      ? new Deno.Command("osascript", {
+          args: [
+            "-e",
+            'tell application "System Events" to keystroke "v" using command down',
+          ],
+        })
+      : resolveLinuxBackend(config) === "wtype"
+      ? new Deno.Command("wtype", { args: ["-M", "ctrl", "v", "-m", "ctrl"] })
+      : new Deno.Command("xdotool", {
+          args: ["key", "--clearmodifiers", "ctrl+v"],
+        });
+  const { success } = await command.output();
+  if (!success) throw new Error("Failed to send paste shortcut");
+
+  // Give the target app time to read the clipboard before restoring it
+  await new Promise((resolve) => setTimeout(resolve, 300));
+  if (previous !== null) {
+    await copyToClipboard(previous);
+  } else if (transient) {
+    // Don't leave the dictation sitting on the clipboard either
+    await copyToClipboard("");
+  }
+}
+
+// Read-back of the final text
+async function speakText(text: string, config: Settings): Promise<void> {
+  if (await isMicLive()) return;
+  try {
+    if (config.readBack === "system") {
+      if (Deno.build.os === "darwin") {
+        await playSound("say", [text]);
+      } else {
+        await playSound("spd-say", ["--wait", text]);
+      }
+      return;
+    }
+
+    if (config.readBack === "openai") {
+      const audio = await withOpenAIKey(config, async (apiKey) => {
+        const response = await providerFetch(
+          "https://api.openai.com/v1/audio/speech",
+          {
+            method: "POST",
+            headers: {
+              Authorization: `Bearer ${apiKey}`,
+              "Content-Type": "application/json",
+            },
+            body: JSON.stringify({
+              model: "tts-1",
+              voice: config.readBackVoice,
+              input: text,
+              response_format: "mp3",
+            }),
+          }
+        );
+        if (!response.ok) {
+          throw new OpenAIError(
+            response.status,
+            await response.text(),
+            retryAfterSeconds(response)
+          );
+        }
+        return new Uint8Array(await response.arrayBuffer());
+      });
+
+      // Generating speech takes a moment; a recording may have started
+      if (await isMicLive()) return;
+      const speechPath = "/tmp/typr-readback.mp3";
+      await Deno.writeFile(speechPath, audio);
+      if (Deno.build.os === "darwin") {
+        await playSound("afplay", [speechPath]);
+      } else {
+        await playSound("ffplay", [
+          "-nodisp",
+          "-autoexit",
+          "-loglevel",
+          "quiet",
+          speechPath,
+        ]);
+      }
+    }
+  } catch (error) {
+    await logToFile("ERROR", "Failed to read back text", error);
+  }
+}
+
+// Audio processing pipeline
+type ProcessResult = {
+  transcription: string;
+  openaiResponse: string;
+  provider: string;
+  stages: Record<string, number>;
+  // True when the dictation was an app command and nothing should be typed
+  handled: boolean;
+  llmStatus: LLMStatus;
+  email: EmailDraft | null;
+};
+
+type EmailDraft = { to: string; subject: string; body: string };
+
+// Per-dictation overrides chosen when the recording is stopped
+type DictationOptions = { copyOnly: boolean; skipLLM: boolean };
+
+const DEFAULT_DICTATION_OPTIONS: DictationOptions = {
+  copyOnly: false,
+  skipLLM: false,
+};
+
+const EMAIL_EXTRACTION_PROMPT = `
+
+Treat the dictation as an email. Reply with only a JSON object with the keys "to" (recipient address if one was dictated, otherwise ""), "subject" and "body" (the finished email text).`;
+
+// The LLM is asked for JSON; anything else is taken as the body
+function parseEmailDraft(response: string): EmailDraft {
+  const json = response.replace(/^```(?:json)?\s*|\s*```$/g, "");
+  try {
+    const data = JSON.parse(json);
+    return {
+      to: String(data.to ?? ""),
+      subject: String(data.subject ?? ""),
+      body: String(data.body ?? ""),
+    };
+  } catch {
+    return { to: "", subject: "", body: response };
+  }
+}
+
+// App-control commands: "typr, open settings", "typr, switch to email mode",
+// "typr, pause". Whisper spells the name a few different ways.
+type AppCommand =
+  | { action: "open-settings" }
+  | { action: "open-logs" }
+  | { action: "switch-mode"; mode: string }
+  | { action: "pause" }
+  | { action: "resume" }
+  | { action: "cancel" };
+
+function parseAppCommand(transcription: string): AppCommand | null {
+  const match = transcription
+    .trim()
+    .toLowerCase()
+    .match(/^(?:hey\s+)?(?:typr|typer|tiper|tipper)[,.!]?\s+(.+?)[.!?]*$/);
+  if (!match) return null;
+
+  const command = match[1];
+  if (/^(?:open|show) (?:the )?settings$/.test(command)) {
+    return { action: "open-settings" };
+  }
+  if (/^(?:open|show) (?:the )?logs?$/.test(command)) {
+    return { action: "open-logs" };
+  }
+  const mode = command.match(/^(?:switch to |use )?(?:the )?([\w-]+) mode$/);
+  if (mode) return { action: "switch-mode", mode: mode[1] };
+  if (/^pause$/.test(command)) return { action: "pause" };
+  if (/^(?:resume|unpause)$/.test(command)) return { action: "resume" };
+  if (/^(?:cancel|never ?mind|scratch that)$/.test(command)) {
+    return { action: "cancel" };
+  }
+  return null;
+}
+
+async function openPath(path: string): Promise<void> {
+  const opener = Deno.build.os === "darwin" ? "open" : "xdg-open";
+  await new Deno.Command(opener, { args: [path] }).output();
+}
+
+async function showLogs(count: number): Promise<void> {
+  if (!(await exists(LOG_FILE))) {
+    console.log("No log entries yet");
+    return;
+  }
+  const lines = (await Deno.readTextFile(LOG_FILE)).trimEnd().split("\n");
+  console.log(lines.slice(-count).join("\n"));
+}
+
+// Show the logs, ready to attach to a bug report
+async function openLogs(): Promise<void> {
+  for (const log of [LOG_FILE, PROVIDER_LOG_FILE, EVENT_LOG_FILE]) {
+    if (await exists(log)) console.log(log);
+  }
+  // Finder can reveal the hidden dotfile; elsewhere open it directly
+  if (Deno.build.os === "darwin") {
+    await new Deno.Command("open", { args: ["-R", LOG_FILE] }).output();
+  } else {
+    await openPath(LOG_FILE);
+  }
+}
+
+async function executeAppCommand(command: AppCommand): Promise<void> {
+  await logToFile("INFO", `App command: ${JSON.stringify(command)}`);
+  const config = await loadSettings();
+
+  switch (command.action) {
+    case "open-settings":
+      await openPath(SETTINGS_FILE);
+      break;
+    case "open-logs":
+      await openLogs();
+      break;
+    case "switch-mode": {
+      const mode = Object.keys(config.modes).find(
+        (name) => name.toLowerCase() === command.mode
+      );
+      if (!mode && command.mode !== "default") {
+        await notify(`❓ Unknown mode "${command.mode}"`);
+        return;
+      }
+      await saveSettings({ ...config, activeMode: mode ?? "default" });
+      await notify(`🎛️ Mode: ${mode ?? "default"}`, "low");
+      break;
+    }
+    case "pause":
+      await saveSettings({ ...config, paused: true });
+      await notify("⏸️ Typr paused - say \"typr, resume\" to continue");
+      break;
+    case "resume":
+      await saveSettings({ ...config, paused: false });
+      await notify("▶️ Typr resumed", "low");
+      break;
+    case "cancel":
+      await notify("🗑️ Dictation discarded", "low");
+      break;
+  }
+}
+
+// List prices in USD per minute of audio; override or add providers with
+// transcriptionPricePerMinute
+const TRANSCRIPTION_PRICES: Record<string, number> = {
+  openai: 0.006,
+  elevenlabs: 0.0067,
+  selfhosted: 0,
+  wyoming: 0,
+};
+
+// Native yes/no dialog; false only when the user says no
+async function askConfirmation(message: string): Promise<boolean> {
+  try {
+    const command =
+      Deno.build.os === "darwin"
+        ? new Deno.Command("osascript", {
+            args: [
+              "-e",
+              `display dialog ${JSON.stringify(message)} with title "Typr" buttons {"Cancel", "Continue"} default button "Continue"`,
+            ],
+            stdout: "null",
+            stderr: "null",
+          })
+        : new Deno.Command("zenity", {
+            args: ["--question", "--title=Typr", `--text=${message}`],
+            stderr: "null",
+          });
+    const { success } = await command.output();
+    return success;
+  } catch (error) {
+    await logToFile("ERROR", "Could not show a confirmation dialog", error);
+    return true;
+  }
+}
+
+// Estimate what uploading the recording will cost and ask first when it's
+// above costConfirmThreshold
+async function approveCost(
+  audioPath: string,
+  config: Settings
+): Promise<boolean> {
+  const provider = config.transcriptionProvider;
+  const price =
+    config.transcriptionPricePerMinute[provider] ??
+    TRANSCRIPTION_PRICES[provider] ??
+    0;
+  // Free providers shouldn't pay for an ffprobe run on every dictation
+  if (!price) return true;
+  const minutes = (await audioDuration(audioPath)) / 60;
+  if (!(minutes > 0)) return true;
+
+  const cost = minutes * price;
+  const dollars = cost.toFixed(2);
+  const summary = `${minutes.toFixed(1)} min via ${provider}, about $${dollars}`;
+  await logToFile("INFO", `Estimated transcription cost: ${summary}`);
+
+  if (config.costConfirmThreshold > 0 && cost >= config.costConfirmThreshold) {
+    return await askConfirmation(`Transcribe ${summary}?`);
+  }
+  if (minutes >= 5) {
+    await notify(`💸 Transcribing ${summary}`, "low");
+  }
+  return true;
+}
+
+// What Whisper tends to produce for silence, music or noise, learned from
+// subtitle training data
+const HALLUCINATED_PHRASES = [
+  "thank you",
+  "thank you for watching",
+  "thanks for watching",
+  "thank you so much for watching",
+  "please subscribe",
+  "like and subscribe",
+  "subtitles by the amara.org community",
+  "see you in the next video",
+  "you",
+  "bye",
+];
+
+// Returns why the transcript looks hallucinated, or null
+function detectHallucination(
+  transcription: string,
+  speechSeconds: number | null
+): string | null {
+  const normalized = transcription
+    .toLowerCase()
+    .replace(/[^\p{L}\p{N}.' ]+/gu, " ")
+    .replace(/\.(?=\s|$)/g, "")
+    .replace(/\s+/g, " ")
+    .trim();
+  // "Thank you." or "Bye." is a real reply when speech was actually heard
+  const heardSpeech = speechSeconds !== null && speechSeconds >= 1;
+  if (!heardSpeech && HALLUCINATED_PHRASES.includes(normalized)) {
+    return "known phrase";
+  }
+
+  // The same short phrase looping over and over
+  const words = normalized.split(" ").filter(Boolean);
+  for (let size = 1; size <= 6; size++) {
+    let run = 0;
+    for (let i = size; i < words.length; i++) {
+      run = words[i] === words[i - size] ? run + 1 : 0;
+      // Four more copies on top of the first
+      if (run >= size * 4) return "repetition";
+    }
+  }
+
+  // Nobody speaks more than ~6 words a second
+  if (speechSeconds !== null && words.length > 6 * speechSeconds + 5) {
+    return "too much text for the speech heard";
+  }
+  return null;
+}
+
+// Names from a contacts file: plain text (one per line), CSV (first column)
+// or a vCard export (FN lines)
+async function loadContacts(config: Settings): Promise<string[]> {
+  if (!config.contactsFile) return [];
+  const path = expandHome(config.contactsFile);
+  try {
+    const content = await Deno.readTextFile(path);
+    if (extname(path).toLowerCase() === ".vcf") {
+      return [...content.matchAll(/^FN[^:]*:(.+)$/gm)].map((match) =>
+        match[1].trim()
+      );
+    }
+    return content
+      .split("\n")
+      .map((line) => line.split(",")[0].replace(/^"|"$/g, "").trim())
+      .filter((name) => name && !name.startsWith("#"));
+  } catch (error) {
+    await logToFile("ERROR", `Failed to read contacts file ${path}`, error);
+    return [];
+  }
+}
+
+function editDistance(a: string, b: string): number {
+  let previous = Array.from({ length: b.length + 1 }, (_, i) => i);
+  for (let i = 1; i <= a.length; i++) {
+    const current = [i];
+    for (let j = 1; j <= b.length; j++) {
+      current[j] = Math.min(
+        previous[j] + 1,
+        current[j - 1] + 1,
+        previous[j - 1] + (a[i - 1] === b[j - 1] ? 0 : 1)
+      );
+    }
+    previous = current;
+  }
+  return previous[b.length];
+}
+
+// Fix near-misses of known names ("Jon Carus" -> "Jon Carrus"). Only
+// capitalised runs of the same word count are considered, and only close
+// matches replaced, so ordinary words are left alone. A short single name
+// is one letter away from plenty of other real names ("Jones" and "Jonas"),
+// so those are only matched from seven letters on, and a word that is
+// itself a known name is never changed.
+function matchEntityNames(text: string, names: string[]): string {
+  const known = [...new Set(names.map((name) => name.trim()))].filter(
+    (name) => name.length >= 4
+  );
+  if (known.length === 0) return text;
+  const exact = new Set(known.map((name) => name.toLowerCase()));
+
+  const words = [...text.matchAll(/[\p{L}\p{N}'’-]+/gu)];
+  const replacements: { start: number; end: number; name: string }[] = [];
+  for (const name of known) {
+    const size = name.split(/\s+/).length;
+    for (let i = 0; i + size <= words.length; i++) {
+      const run = words.slice(i, i + size);
+      if (!/^\p{Lu}/u.test(run[0][0])) continue;
+      const start = run[0].index!;
+      const last = run[size - 1];
+      const end = last.index! + last[0].length;
+      const candidate = text.slice(start, end);
+      if (candidate === name) continue;
+
+      const a = candidate.toLowerCase();
+      const b = name.toLowerCase();
+      if (exact.has(a)) continue;
+      if (size === 1 && Math.max(a.length, b.length) < 7) continue;
+      const similarity = 1 - editDistance(a, b) / Math.max(a.length, b.length);
+      if (similarity >= 0.8) replacements.push({ start, end, name });
+    }
+  }
+
+  // Apply right to left so earlier offsets stay valid, skipping overlaps
+  replacements.sort((a, b) => b.start - a.start);
+  let result = text;
+  let limit = Infinity;
+  for (const { start, end, name } of replacements) {
+    if (end > limit) continue;
+    result = result.slice(0, start) + name + result.slice(end);
+    limit = start;
+  }
+  return result;
+}
+
+function escapeRegExp(text: string): string {
+  return text.replace(/[.*+?^${}()|[\]\\]/g, "\\$&");
+}
+
+// Whole-word, case-insensitive replacement rules
+function applyReplacements(
+  text: string,
+  replacements: Record<string, string>
+): string {
+  for (const [from, to] of Object.entries(replacements)) {
+    if (!from) continue;
+    const pattern = new RegExp(`(?<!\\w)${escapeRegExp(from)}(?!\\w)`, "gi");
+    text = text.replace(pattern, () => to);
+  }
+  return text;
+}
+
+const capitalize = (word: string) =>
+  word.charAt(0).toUpperCase() + word.slice(1);
+
+const IDENTIFIER_CASES: Record<string, (words: string[]) => string> = {
+  camel: (words) =>
+    words.map((word, i) => (i ? capitalize(word) : word)).join(""),
+  pascal: (words) => words.map(capitalize).join(""),
+  snake: (words) => words.join("_"),
+  kebab: (words) => words.join("-"),
+  constant: (words) => words.join("_").toUpperCase(),
+};
+
+// "camel case user profile id" -> userProfileId. The identifier runs until
+// the next punctuation mark or a spoken "end case".
+function applyIdentifierCommands(text: string): string {
+  return text.replace(
+    /\b(camel|pascal|snake|kebab|constant)[ -]case,?\s+(.+?)(?:,?\s+end case\b|(?=[.,!?;:\n]|$))/gi,
+    (_, style: string, phrase: string) =>
+      IDENTIFIER_CASES[style.toLowerCase()](
+        phrase
+          .toLowerCase()
+          .split(/[\s-]+/)
+          .map((word) => word.replace(/[^\p{L}\p{N}]/gu, ""))
+          .filter(Boolean)
+      )
+  );
+}
+
+// Spoken email addresses and URLs. A domain must end in a known top-level
+// domain, so "the dot product" is left alone.
+const TOP_LEVEL_DOMAINS =
+  "com|org|net|io|dev|app|ai|co|edu|gov|me|info|xyz|uk|us|ca|de|fr|nl|eu|au|ch|se|jp|in";
+
+// Ordinary words that come before "at" or "dot" in speech: "look at
+// example dot com" is a URL, not look@example.com, and "the dot com bubble"
+// is no URL at all
+const NOT_ADDRESS_WORDS = new Set(
+  "is are was were be been look looking i you we they me us him her them he she it its this that these those here there a an the my your our their his some any in on at to of for with and or but site website page docs available live hosted up over".split(" ")
+);
+
+const SPOKEN_WORD = "[\\p{L}\\p{N}]+";
+const SPOKEN_DOMAIN = `${SPOKEN_WORD}(?:\\s+(?:dot|dash|hyphen)\\s+${SPOKEN_WORD})*\\s+dot\\s+(?:${TOP_LEVEL_DOMAINS})\\b`;
+const SPOKEN_EMAIL = new RegExp(
+  `\\b(${SPOKEN_WORD}(?:\\s+(?:dot|underscore|dash|hyphen|plus)\\s+${SPOKEN_WORD})*)\\s+at\\s+(${SPOKEN_DOMAIN})`,
+  "giu"
+);
+const SPOKEN_URL = new RegExp(
+  `\\b((?:https?\\s+colon\\s+slash\\s+slash\\s+)?${SPOKEN_DOMAIN}(?:\\s+slash\\s+${SPOKEN_WORD}(?:\\s+(?:dot|dash|hyphen|underscore)\\s+${SPOKEN_WORD})*)*)`,
+  "giu"
+);
+
+const SPOKEN_SYMBOLS: Record<string, string> = {
+  dot: ".",
+  underscore: "_",
+  dash: "-",
+  hyphen: "-",
+  plus: "+",
+  slash: "/",
+};
+
+function joinSpokenAddress(phrase: string): string {
+  return phrase
+    .replace(/\s+colon\s+slash\s+slash\s+/gi, "://")
+    .replace(
+      /\s+(dot|underscore|dash|hyphen|plus|slash)\s+/gi,
+      (_, word: string) => SPOKEN_SYMBOLS[word.toLowerCase()]
+    )
+    .toLowerCase();
+}
+
+// Single letters ("vitamin D dot com", "a dot in the middle") and common
+// words are too likely to be speech to start an address
+function isAddressWord(word: string): boolean {
+  return word.length > 1 && !NOT_ADDRESS_WORDS.has(word.toLowerCase());
+}
+
+function normalizeAddresses(text: string): string {
+  return text
+    .replace(SPOKEN_EMAIL, (match, user: string, domain: string) =>
+      !isAddressWord(user) || !isAddressWord(domain.split(/\s+/)[0])
+        ? match
+        : `${joinSpokenAddress(user)}@${joinSpokenAddress(domain)}`
+    )
+    .replace(SPOKEN_URL, (match) =>
+      /^https?\s/i.test(match) || isAddressWord(match.split(/\s+/)[0])
+        ? joinSpokenAddress(match)
+        : match
+    );
+}
+
+// Acronyms map short forms to long ones ({"k8s": "Kubernetes"})
+function applyAcronyms(text: string, config: Settings): string {
+  switch (config.acronymDirection) {
+    case "off":
+      return text;
+    case "expand":
+      return applyReplacements(text, config.acronyms);
+    case "contract":
+      return applyReplacements(
+        text,
+        Object.fromEntries(
+          Object.entries(config.acronyms).map(([short, long]) => [long, short])
+        )
+      );
+  }
+}
+
+const NUMBER_WORDS: Record<string, number> = {
+  one: 1,
+  two: 2,
+  three: 3,
+  four: 4,
+  five: 5,
+  six: 6,
+  seven: 7,
+  eight: 8,
+  nine: 9,
+};
+
+const FRACTION_DENOMINATORS: Record<string, number> = {
+  half: 2,
+  halves: 2,
+  third: 3,
+  thirds: 3,
+  quarter: 4,
+  quarters: 4,
+  fourth: 4,
+  fourths: 4,
+  fifth: 5,
+  fifths: 5,
+  sixth: 6,
+  sixths: 6,
+  eighth: 8,
+  eighths: 8,
+};
+
+const FRACTION_GLYPHS: Record<string, string> = {
+  "1/2": "½",
+  "1/3": "⅓",
+  "2/3": "⅔",
+  "1/4": "¼",
+  "3/4": "¾",
+  "1/5": "⅕",
+  "2/5": "⅖",
+  "3/5": "⅗",
+  "4/5": "⅘",
+  "1/6": "⅙",
+  "5/6": "⅚",
+  "1/8": "⅛",
+  "3/8": "⅜",
+  "5/8": "⅝",
+  "7/8": "⅞",
+};
+
+// "three quarters" -> ¾ or 3/4
+function formatFractions(text: string, config: Settings): string {
+  if (config.spokenFractions === "keep") return text;
+  return text.replace(
+    /\b(one|two|three|four|five|six|seven|eight|nine|\d)[ -](halves|half|thirds?|quarters?|fourths?|fifths?|sixths?|eighths?)\b/gi,
+    (match, numerator: string, denominator: string) => {
+      const top = NUMBER_WORDS[numerator.toLowerCase()] ?? Number(numerator);
+      const bottom = FRACTION_DENOMINATORS[denominator.toLowerCase()];
+      if (top >= bottom) return match;
+      const fraction = `${top}/${bottom}`;
+      return config.spokenFractions === "glyph"
+        ? FRACTION_GLYPHS[fraction] ?? fraction
+        : fraction;
+    }
+  );
+}
+
+function numberSeparators(locale: string): { group: string; decimal: string } {
+  let parts: Intl.NumberFormatPart[];
+  try {
+    parts = new Intl.NumberFormat(locale).formatToParts(12345.6);
+  } catch {
+    parts = new Intl.NumberFormat("en").formatToParts(12345.6);
+  }
+  return {
+    group: parts.find((part) => part.type === "group")?.value ?? ",",
+    decimal: parts.find((part) => part.type === "decimal")?.value ?? ".",
+  };
+}
+
+// Numbers are read in the dictation language's convention and rewritten in
+// numberLocale's. Anything that doesn't parse cleanly (versions, IPs, lists)
+// is left alone, and four-digit numbers such as years stay ungrouped.
+function formatNumbers(text: string, config: Settings): string {
+  if (!config.numberLocale) return text;
+  const source = config.language === "auto" ? "en" : config.language;
+  const { group, decimal } = numberSeparators(source);
+  const grouped = new RegExp(
+    `^\\d{1,3}(?:${escapeRegExp(group)}\\d{3})+(?:${escapeRegExp(decimal)}(\\d+))?$`
+  );
+  const plain = new RegExp(`^\\d+(?:${escapeRegExp(decimal)}(\\d+))?$`);
+
+  const numbers = /(?<![\w.,])\d(?:[\d.,]*\d)?(?!\w|[.,]\d)/g;
+  return text.replace(numbers, (token) => {
+    const groupedMatch = grouped.exec(token);
+    const match = groupedMatch ?? plain.exec(token);
+    if (!match || token.replace(/\D/g, "").length > 15) return token;
+
+    const digits = match[1]?.length ?? 0;
+    const value = Number(token.split(group).join("").replace(decimal, "."));
+    try {
+      return new Intl.NumberFormat(config.numberLocale, {
+        useGrouping: !config.numberGrouping
+          ? false
+          : groupedMatch
+          ? "always"
+          : "min2",
+        minimumFractionDigits: digits,
+        maximumFractionDigits: digits,
+      }).format(value);
+    } catch {
+      return token;
+    }
+  });
+}
+
+// Words title case leaves lowercase unless they start the line
+const TITLE_CASE_MINOR_WORDS = new Set(
+  "a an and as at but by for in nor of on or the to vs via".split(" ")
+);
+
+function titleCase(text: string): string {
+  return text
+    .split("\n")
+    .map((line) =>
+      line.replace(/\p{L}[\p{L}']*/gu, (word, offset: number) =>
+        offset > 0 && TITLE_CASE_MINOR_WORDS.has(word.toLowerCase())
+          ? word.toLowerCase()
+          : word[0].toUpperCase() + word.slice(1)
+      )
+    )
+    .join("\n");
+}
+
+// Deterministic casing fixes that hold whatever the casing style
+function applyCasingRules(text: string, config: Settings): string {
+  if (config.capitalizeI) {
+    // "i.e." stays as it is
+    text = text.replace(/(?<!\w)i(?=$|[\s'’,!?;:]|\.(?!\w))/g, "I");
+  }
+  // Vocabulary terms keep their canonical spelling ("GitHub", "iOS")
+  for (const term of config.vocabulary) {
+    const pattern = new RegExp(`(?<!\\w)${escapeRegExp(term)}(?!\\w)`, "gi");
+    text = text.replace(pattern, () => term);
+  }
+  return text;
+}
+
+// Mask ("f***") or drop listed words before anything is typed
+function filterProfanity(text: string, config: Settings): string {
+  if (config.profanityFilter === "off" || !config.profanityWords.length) {
+    return text;
+  }
+  const words = config.profanityWords.map(escapeRegExp).join("|");
+  const pattern = new RegExp(`(?<!\\w)(?:${words})(?!\\w)`, "gi");
+
+  if (config.profanityFilter === "mask") {
+    return text.replace(
+      pattern,
+      (word) => word[0] + "*".repeat(word.length - 1)
+    );
+  }
+  return text
+    .replace(pattern, "")
+    .replace(/[ \t]{2,}/g, " ")
+    .replace(/[ \t]+([.,!?;:])/g, "$1")
+    .trim();
+}
+
+// Final formatting pass: "auto" keeps the casing the provider and LLM
+// produced, the others enforce it
+function formatOutput(text: string, config: Settings): string {
+  text = formatNumbers(formatFractions(text, config), config);
+
+  if (!config.punctuation) {
+    // Only sentence punctuation goes: "don't", "re-run" and "3.5" survive
+    text = text
+      .replace(/[.,!?;:…]+(?=\s|$)/g, "")
+      .replace(/["“”()]/g, "")
+      .replace(/[ \t]+/g, " ")
+      .trim();
+  }
+
+  switch (config.casing) {
+    case "auto":
+      return applyCasingRules(text, config);
+    case "lower":
+      // Verbatim lowercase means no exceptions
+      return text.toLowerCase();
+    case "sentence":
+      return applyCasingRules(
+        text.replace(
+          /(^|[.!?]\s+|\n\s*)(\p{Ll})/gu,
+          (_, before, letter) => before + letter.toUpperCase()
+        ),
+        config
+      );
+    case "title":
+      return applyCasingRules(titleCase(text), config);
+  }
+}
+
+async function processAudioFile(
+  audioPath: string,
+  streamedTranscription: string | null = null,
+  screenTerms: string[] = [],
+  options: DictationOptions = DEFAULT_DICTATION_OPTIONS
+): Promise<ProcessResult> {
+  let config = await loadActiveSettings();
+  if (screenTerms.length) {
+    config.whisperPrompt += `\n\nOn screen: ${screenTerms.join(", ")}.`;
+  }
+  if (config.providerRoutes.length && !streamedTranscription) {
+    config = await routeByLength(config, audioPath);
+  }
+  const stages: Record<string, number> = {};
+  let stageStart = Date.now();
+  const hasProvider = isTranscriptionConfigured(config);
+
+  if (!hasProvider && !config.useLocalWhisper) {
+    await logToFile(
+      "ERROR",
+      "No transcription provider configured and local Whisper not enabled"
+    );
+    throw new TyprFailure(
+      "no-api-key",
+      "No transcription provider configured and local Whisper not enabled"
+    );
+  }
+
+  // Accidental presses and muted mics shouldn't cost an API call
+  let speechSeconds: number | null = null;
+  if (!streamedTranscription && config.speechThresholdDb < 0) {
+    const speech = await measureSpeech(audioPath, config.speechThresholdDb);
+    speechSeconds = speech;
+    if (speech < 0.3) {
+      await logToFile("INFO", `No speech detected (${speech.toFixed(2)}s)`);
+      await notify("🤷 Nothing heard - check your mic", "normal");
+      return {
+        transcription: "",
+        openaiResponse: "",
+        provider: "none",
+        stages,
+        handled: true,
+        llmStatus: "skipped",
+        email: null,
+      };
+    }
+  }
+
+  let transcription = streamedTranscription ?? "";
+  let provider: string = config.transcriptionProvider;
+  let attempt = 1;
+
+  // Try local Whisper first if enabled
+  if (
+    !transcription &&
+    config.useLocalWhisper &&
+    (await isWhisperAvailable(config))
+  ) {
+    try {
+      transcription = await transcribeWithLocalWhisper(audioPath, config);
+      provider = "local";
+    } catch (error) {
+      await logToFile(
+        "ERROR",
+        `Local Whisper failed, falling back to ${config.transcriptionProvider}`,
+        error
+      );
+    }
+  }
+
+  // Use the cloud provider if local Whisper wasn't used or failed
+  if (!transcription && hasProvider) {
+    if (!(await approveCost(audioPath, config))) {
+      await notify(`💸 Transcription cancelled - recording kept at ${audioPath}`);
+      return {
+        transcription: "",
+        openaiResponse: "",
+        provider: "none",
+        stages,
+        handled: true,
+        llmStatus: "skipped",
+        email: null,
+      };
+    }
+
+    let result = await transcribeLongAudio(audioPath, config);
+    if (
+      config.minAvgLogprob < 0 &&
+      typeof result.avgLogprob === "number" &&
+      result.avgLogprob < config.minAvgLogprob
+    ) {
+      const retry = await retranscribeLowConfidence(audioPath, config, result);
+      if (retry) {
+        result = retry;
+        attempt = 2;
+      }
+    }
+    transcription = result.text;
+
+    // The detected language picks the prompts and replacements from here on
+    if (config.language === "auto" && result.language) {
+      await logToFile("INFO", `Detected language: ${result.language}`);
+      if (!isLanguageAllowed(config, result.language)) {
+        const name = languageName(result.language);
+        if (config.otherLanguages === "refuse") {
+          await logToFile("INFO", `Refused ${name}: ${result.text}`);
+          await notify(
+            `🌐 Ignored speech in ${name} - not an allowed language`
+          );
+          return {
+            transcription: result.text,
+            openaiResponse: "",
+            provider,
+            stages,
+            handled: true,
+            llmStatus: "skipped",
+            email: null,
+          };
+        }
+        await notify(
+          `🌐 Heard ${name}, which isn't an allowed language`,
+          "low"
+        );
+      }
+      config = applyLanguage(config, result.language);
+    }
+  }
+
+  stages.transcribe = Date.now() - stageStart;
+
+  const hallucination = config.hallucinationFilter
+    ? detectHallucination(transcription, speechSeconds)
+    : null;
+  if (hallucination) {
+    await logToFile(
+      "INFO",
+      `Suppressed likely hallucination (${hallucination}): ${transcription}`
+    );
+    await notify("🤔 That looked like a transcription glitch - nothing typed");
+    return {
+      transcription,
+      openaiResponse: "",
+      provider,
+      stages,
+      handled: true,
+      llmStatus: "skipped",
+      email: null,
+    };
+  }
+
+  // Commands are short, so check them before the minimum length
+  const command = parseAppCommand(transcription);
+  if (command || config.paused) {
+    if (command) {
+      await executeAppCommand(command);
+    } else {
+      await notify("⏸️ Typr is paused - say \"typr, resume\" to continue");
+    }
+    return {
+      transcription,
+      openaiResponse: "",
+      provider,
+      stages,
+      handled: true,
+      llmStatus: "skipped",
+      email: null,
+    };
+  }
+
+  if (!transcription || transcription.length < 10) {
+    throw new TyprFailure("no-transcript", "Transcription failed or too short");
+  }
+
+  // Apply simple replacements
+  if (config.useFormattingKeywords) {
+    transcription = applyReplacements(transcription, config.formattingKeywords);
+  }
+  if (config.identifierCommands) {
+    transcription = applyIdentifierCommands(transcription);
+  }
+  if (config.normalizeAddresses) {
+    transcription = normalizeAddresses(transcription);
+  }
+  transcription = applyReplacements(transcription, config.replacements);
+
+  // Clean up whitespace
+  transcription = transcription
+    .split("\n")
+    .map((line) => line.trim())
+    .filter((line) => line.length > 0)
+    .join("\n");
+
+  await publishEvent(config, {
+    type: "transcript",
+    final: false,
+    text: transcription,
+    timestamp: new Date().toISOString(),
+  });
+
+  let openaiResponse = transcription;
+
+  // Process with the LLM if "note to the editor" is mentioned
+  let llmStatus: LLMStatus = "skipped";
+  if (!options.skipLLM && !config.skipLLM && isLLMConfigured(config)) {
+    stageStart = Date.now();
+    const library = config.prompt ? await loadPromptLibrary() : {};
+    if (config.prompt && !findPrompt(library, config.prompt)) {
+      await notify(`⚠️ No prompt ${config.prompt} - edited with llmPrompt`);
+    }
+    try {
+      openaiResponse = await processWithLLM(
+        transcription,
+        config.emailOutput === "off"
+          ? config
+          : { ...config, llmPrompt: config.llmPrompt + EMAIL_EXTRACTION_PROMPT }
+      );
+      llmStatus = "ok";
+      // Email replies are JSON, checked by parseEmailDraft instead
+      if (config.validateLLMResponse && config.emailOutput === "off") {
+        openaiResponse = cleanLLMResponse(openaiResponse);
+        const problem = llmResponseProblem(openaiResponse, transcription);
+        if (problem) {
+          await logToFile("ERROR", `Discarded LLM response: ${problem}`);
+          await notify(
+            "⚠️ The edit didn't match the dictation - typed the raw transcript"
+          );
+          openaiResponse = transcription;
+          llmStatus = "failed";
+        }
+      }
+    } catch (error) {
+      llmStatus = "failed";
+      await logToFile("ERROR", "LLM processing failed", error);
+      await notify(
+        "⚠️ Editing failed - typed the raw transcript (typr reedit to retry)"
+      );
+    }
+    stages.llm = Date.now() - stageStart;
+  }
+
+  // Deterministic, so names get fixed even without an LLM
+  if (config.entityMatching) {
+    openaiResponse = matchEntityNames(
+      openaiResponse,
+      [...config.vocabulary, ...(await loadContacts(config))]
+    );
+  }
+  openaiResponse = applyAcronyms(openaiResponse, config);
+
+  let email: EmailDraft | null = null;
+  if (config.emailOutput !== "off") {
+    email =
+      llmStatus === "ok"
+        ? parseEmailDraft(openaiResponse)
+        : { to: "", subject: "", body: openaiResponse };
+    email.body = filterProfanity(formatOutput(email.body, config), config);
+    openaiResponse = email.body;
+  } else {
+    openaiResponse = filterProfanity(
+      formatOutput(openaiResponse, config),
+      config
+    );
+  }
+
+  if (config.maxLength > 0 && openaiResponse.length > config.maxLength) {
+    openaiResponse = await fitToLength(openaiResponse, config);
+    if (email) email.body = openaiResponse;
+  }
+
+  await publishEvent(config, {
+    type: "transcript",
+    final: true,
+    text: openaiResponse,
+    timestamp: new Date().toISOString(),
+  });
+
+  await addHistoryEntry(
+    config,
+    transcription,
+    openaiResponse,
+    llmStatus,
+    attempt
+  );
+  await appendToSession(openaiResponse, config);
+
+  return {
+    transcription,
+    openaiResponse,
+    provider,
+    stages,
+    handled: false,
+    llmStatus,
+    email,
+  };
+}
+
+// Pieces of at most `limit` characters, cut at a paragraph, sentence or word
+// boundary when one is reasonably close to the limit
+function splitByLength(text: string, limit: number): string[] {
+  const parts: string[] = [];
+  let rest = text.trim();
+  while (rest.length > limit) {
+    const window = rest.slice(0, limit + 1);
+    const sentenceEnd = Math.max(
+      -1,
+      ...[...window.matchAll(/[.!?](?=\s)/g)].map((match) => match.index! + 1)
+    );
+    const cut =
+      [window.lastIndexOf("\n"), sentenceEnd, window.lastIndexOf(" ")].find(
+        (at) => at > limit / 2
+      ) ?? limit;
+    parts.push(rest.slice(0, cut).trimEnd());
+    rest = rest.slice(cut).trimStart();
+  }
+  if (rest) parts.push(rest);
+  return parts;
+}
+
+// Chat messages of whole sentences, packed up to `limit` characters. Each
+// paragraph starts a new message; a sentence longer than the limit is split
+// at word boundaries.
+function splitIntoMessages(text: string, limit: number): string[] {
+  const messages: string[] = [];
+  for (const paragraph of text.split(/\n+/)) {
+    let current = "";
+    const sentences = paragraph
+      .trim()
+      .split(/(?<=[.!?])\s+/)
+      .flatMap((sentence) => splitByLength(sentence, limit));
+    for (const sentence of sentences) {
+      if (current && current.length + 1 + sentence.length > limit) {
+        messages.push(current);
+        current = sentence;
+      } else {
+        current = current ? `${current} ${sentence}` : sentence;
+      }
+    }
+    if (current) messages.push(current);
+  }
+  return messages;
+}
+
+// How the result is broken into separately sent pieces, if at all
+function outputParts(text: string, config: Settings): string[] {
+  if (config.messageChunkSize > 0) {
+    const limit =
+      config.maxLength > 0
+        ? Math.min(config.messageChunkSize, config.maxLength)
+        : config.messageChunkSize;
+    return splitIntoMessages(text, limit);
+  }
+  return config.maxLength > 0 ? splitByLength(text, config.maxLength) : [text];
+}
+
+const SHORTEN_PROMPT = (limit: number) =>
+  `Shorten the following text to at most ${limit} characters. Keep its meaning, tone and language. Reply with only the shortened text.`;
+
+// Make the result fit the mode's maxLength. Splitting happens when typing;
+// truncating and summarizing change the text itself, and always say so.
+async function fitToLength(text: string, config: Settings): Promise<string> {
+  const limit = config.maxLength;
+  if (config.overflow === "summarize" && isLLMConfigured(config)) {
+    try {
+      const shorter = await processWithLLM(text, {
+        ...config,
+        llmPrompt: SHORTEN_PROMPT(limit),
+      });
+      if (shorter.length <= limit) {
+        await notify(`✂️ Shortened to fit ${limit} characters`, "low");
+        return shorter;
+      }
+      text = shorter;
+    } catch (error) {
+      await logToFile("ERROR", "Shortening with the LLM failed", error);
+    }
+  }
+  if (config.overflow === "split") return text;
+
+  await notify(`✂️ Cut to ${limit} characters (was ${text.length})`);
+  return splitByLength(text, limit - 1)[0] + "…";
+}
+
+// Global shortcut setup instructions
+function showShortcutInstructions(): void {
+  const scriptPath = new URL(import.meta.url).pathname;
+  const command = `${Deno.execPath()} run --allow-all "${scriptPath}" toggle`;
+
+  const platformInstructions =
+    Deno.build.os === "linux"
+      ? `🐧 Linux Setup:
+1. For i3: Add to ~/.config/i3/config:
+   bindsym --release Mod4+Shift+space exec "${command}"
+   (Note: --release flag prevents multiple fires)
+   Then run: i3-msg reload
+
+2. For GNOME: Settings > Keyboard > Custom Shortcuts
+   - Name: Typr Toggle
+   - Command: ${command}
+   - Shortcut: Super+Shift+Space
+   (GNOME typically fires on key release by default)
+
+3. For KDE: System Settings > Shortcuts > Custom Shortcuts`
+      : Deno.build.os === "darwin"
+      ? `🍎 macOS Setup:
+1. Install a shortcut manager like Karabiner-Elements or BetterTouchTool
+2. Or use Automator + System Preferences:
+   - Create new 'Quick Action' in Automator
+   - Add 'Run Shell Script' action
+   - Script: ${command}
+   - Save as 'Typr Toggle'
+   - System Preferences > Keyboard > Shortcuts > Services
+   - Assign ⌘⇧Space to 'Typr Toggle'`
+      : "Manual setup required for your OS";
+
+  console.log(`🔗 Global Shortcut Setup
+========================
+
+To enable toggle recording, bind this command to a keyboard shortcut:
+Command: ${command}
+
+${platformInstructions}
+
+💡 How it works:
+   - First press: Starts recording
+   - Second press: Stops recording and types the result
+
+📊 Monitor logs in real-time:
+   tail -f ${LOG_FILE}    # Watch logs`);
+}
+
+// Everything after the recording stopped that isn't transcription or the
+// LLM (reading settings, typing, post-type keys) counts as "output"
+async function recordRunMetrics(
+  result: ProcessResult,
+  config: Settings,
+  stoppedAt: number,
+  audioPath: string
+): Promise<void> {
+  const total = Date.now() - stoppedAt;
+  const measured = Object.values(result.stages).reduce((a, b) => a + b, 0);
+  const run: MetricsRun = {
+    timestamp: new Date().toISOString(),
+    provider: result.provider,
+    llmProvider: isLLMConfigured(config) ? config.llmProvider : "none",
+    stages: { ...result.stages, output: total - measured, total },
+  };
+  await recordMetrics(run);
+  await logDictationEvent({
+    ...run,
+    event: "dictation",
+    mode: config.activeMode,
+    llmStatus: result.llmStatus,
+    handled: result.handled,
+    audioBytes: await fileSize(audioPath),
+    transcriptChars: result.transcription.length,
+    outputChars: result.handled ? 0 : result.openaiResponse.length,
+  });
+}
+
+async function recordRunFailure(
+  error: unknown,
+  stoppedAt: number,
+  audioPath: string
+): Promise<void> {
+  await logDictationEvent({
+    timestamp: new Date().toISOString(),
+    event: "failure",
+    durationMs: Date.now() - stoppedAt,
+    audioBytes: await fileSize(audioPath),
+    error: {
+      code: classifyFailure(error),
+      provider: error instanceof ProviderError ? error.provider : undefined,
+      status: error instanceof ProviderError ? error.status : undefined,
+      message: error instanceof Error ? error.message : String(error),
+    },
+  });
+}
+
+async function fileSize(path: string): Promise<number | null> {
+  try {
+    return (await Deno.stat(path)).size;
+  } catch {
+    return null;
+  }
+}
+
+async function logDictationEvent(
+  event: Record<string, unknown>
+): Promise<void> {
+  try {
+    const { eventLog, activeMode } = await loadSettings();
+    if (!eventLog) return;
+    await rotateIfLarge(EVENT_LOG_FILE, EVENT_LOG_MAX_BYTES);
+    await Deno.writeTextFile(
+      EVENT_LOG_FILE,
+      JSON.stringify({ mode: activeMode, ...event }) + "\n",
+      { append: true }
+    );
+  } catch (error) {
+    await logToFile("ERROR", "Failed to write event log", error);
+  }
+}
+
+// Form filling: "name John Smith, email john at example dot com" goes into
+// the fields labelled Name and Email of the focused window. macOS only, via
+// the accessibility tree.
+type FormField = { index: number; label: string };
+
+const MAC_FORM_FIELDS_SCRIPT = `function run() {
+  const events = Application("System Events");
+  const proc = events.processes.whose({ frontmost: true })[0];
+  const roles = ["AXTextField", "AXTextArea", "AXComboBox"];
+  const labels = ["AXDescription", "AXTitle", "AXPlaceholderValue", "AXHelp"];
+  const fields = [];
+  proc.windows[0].entireContents().forEach((element, index) => {
+    let role = "";
+    try { role = element.role(); } catch (e) { return; }
+    if (!roles.includes(role)) return;
+    let label = "";
+    for (const name of labels) {
+      try { label = element.attributes.byName(name).value() || ""; } catch (e) {}
+      if (label) break;
+    }
+    if (label) fields.push({ index, label });
+  });
+  return JSON.stringify(fields);
+}`;
+
+const MAC_FOCUS_FIELD_SCRIPT = `function run(argv) {
+  const events = Application("System Events");
+  const proc = events.processes.whose({ frontmost: true })[0];
+  const element = proc.windows[0].entireContents()[Number(argv[0])];
+  element.attributes.byName("AXFocused").value = true;
+}`;
+
+async function getFormFields(): Promise<FormField[]> {
+  const { success, stdout } = await new Deno.Command("osascript", {
+    args: ["-l", "JavaScript", "-e", MAC_FORM_FIELDS_SCRIPT],
+    stderr: "null",
+  }).output();
+  if (!success) return [];
+  return JSON.parse(new TextDecoder().decode(stdout));
+}
+
+// Split the dictation at every spoken field label, in the order spoken
+function matchFormFields(
+  text: string,
+  fields: FormField[]
+): { field: FormField; value: string }[] {
+  const hits: { field: FormField; start: number; end: number }[] = [];
+  for (const field of fields) {
+    const label = field.label.replace(/[:*]/g, "").trim();
+    if (!label) continue;
+    const pattern = new RegExp(`(?<!\\w)${escapeRegExp(label)}(?!\\w)`, "i");
+    const match = pattern.exec(text);
+    if (match && !hits.some((hit) => hit.start === match.index)) {
+      hits.push({
+        field,
+        start: match.index,
+        end: match.index + match[0].length,
+      });
+    }
+  }
+  hits.sort((a, b) => a.start - b.start);
+
+  return hits.map((hit, i) => {
+    let value = text
+      .slice(hit.end, hits[i + 1]?.start ?? text.length)
+      .replace(/^[\s:,-]*(?:is\s+)?|[\s,.;]*$/g, "");
+    if (/e-?mail/i.test(hit.field.label)) {
+      value = value
+        .replace(/\s+at\s+/gi, "@")
+        .replace(/\s+dot\s+/gi, ".")
+        .replace(/\s+/g, "")
+        .toLowerCase();
+    }
+    return { field: hit.field, value };
+  });
+}
+
+// Returns false when nothing could be routed, so the text gets typed as usual
+async function fillForm(text: string, config: Settings): Promise<boolean> {
+  if (Deno.build.os !== "darwin") {
+    await logToFile("INFO", "Form filling is only supported on macOS");
+    return false;
+  }
+
+  const entries = matchFormFields(text, await getFormFields());
+  if (entries.length === 0) {
+    await notify("📝 No matching form fields - typing instead", "low");
+    return false;
+  }
+
+  for (const { field, value } of entries) {
+    await new Deno.Command("osascript", {
+      args: [
+        "-l",
+        "JavaScript",
+        "-e",
+        MAC_FOCUS_FIELD_SCRIPT,
+        String(field.index),
+      ],
+      stderr: "null",
+    }).output();
+    await injectText(value, config);
+  }
+  await notify(
+    `📝 Filled ${entries.map(({ field }) => field.label).join(", ")}`,
+    "low"
+  );
+  return true;
+}
+
+// `typr scratchpad [open|copy|insert|clear]`: dictations collected with the
+// scratchpad output can be edited, then copied or typed in one go
+async function scratchpadCommand(action = "open"): Promise<void> {
+  const config = await loadActiveSettings();
+  const path = expandHome(config.scratchpadFile);
+  const content = (await exists(path)) ? await Deno.readTextFile(path) : "";
+
+  switch (action) {
+    case "open":
+      if (!content) await Deno.writeTextFile(path, "");
+      await openPath(path);
+      break;
+    case "copy":
+      await copyToClipboard(content.trimEnd());
+      await notify("📋 Scratchpad copied to clipboard", "low");
+      break;
+    case "insert":
+      await typeText(content.trimEnd(), config);
+      break;
+    case "clear":
+      await Deno.writeTextFile(path, "");
+      await notify("🗒️ Scratchpad cleared", "low");
+      break;
+    default:
+      console.error("Usage: typr scratchpad [open|copy|insert|clear]");
+      Deno.exit(1);
+  }
+}
+
+// Dictation sessions: while one is running every dictation is also appended
+// to a single Markdown document
+const sessionSchema = z.object({
+  name: z.string(),
+  path: z.string(),
+  startedAt: z.string(),
+});
+
+type Session = z.infer<typeof sessionSchema>;
+
+async function loadSession(): Promise<Session | null> {
+  try {
+    return sessionSchema.parse(
+      JSON.parse(await Deno.readTextFile(SESSION_FILE))
+    );
+  } catch {
+    return null;
+  }
+}
+
+async function appendToSession(text: string, config: Settings): Promise<void> {
+  const session = await loadSession();
+  if (!session || !text.trim()) return;
+  const time = new Date().toLocaleTimeString("sv-SE").slice(0, 5);
+  await Deno.writeTextFile(
+    session.path,
+    `### ${time} · ${config.activeMode}\n\n${text.trim()}\n\n`,
+    { append: true }
+  );
+}
+
+const SESSION_SUMMARY_PROMPT = `You are given a dictation session: a series of timestamped dictations. Write a concise summary in Markdown: a short overview paragraph followed by bullet points of the key points, decisions and open questions. Reply with only the summary.`;
+
+async function sessionCommand(
+  action = "status",
+  name?: string,
+  summarize = false
+): Promise<void> {
+  const config = await loadSettings();
+  const session = await loadSession();
+
+  switch (action) {
+    case "start": {
+      if (session) {
+        console.error(`❌ Session "${session.name}" is already running`);
+        Deno.exit(1);
+      }
+      const now = new Date();
+      const title = name ?? "Dictation session";
+      const slug = title.toLowerCase().replace(/[^\p{L}\p{N}]+/gu, "-");
+      const stamp = now.toLocaleString("sv-SE").slice(0, 16).replace(/\D/g, "");
+      const dir = expandHome(config.sessionDir);
+      await Deno.mkdir(dir, { recursive: true });
+      const path = join(dir, `${stamp}-${slug}.md`);
+      await Deno.writeTextFile(
+        path,
+        `# ${title}\n\nStarted ${now.toLocaleString("sv-SE").slice(0, 16)}\n\n`
+      );
+      await Deno.writeTextFile(
+        SESSION_FILE,
+        JSON.stringify(
+          { name: title, path, startedAt: now.toISOString() },
+          null,
+          2
+        )
+      );
+      await notify(`📓 Session started: ${title}`, "low");
+      console.log(`📓 Recording dictations to ${path}`);
+      break;
+    }
+    case "end": {
+      if (!session) {
+        console.error("❌ No session is running");
+        Deno.exit(1);
+      }
+      await Deno.remove(SESSION_FILE);
+      const content = await Deno.readTextFile(session.path);
+      if (summarize || config.summarizeSessions) {
+        const active = await loadActiveSettings();
+        if (!isLLMConfigured(active)) {
+          console.error(`❌ ${active.llmProvider} is not configured`);
+        } else {
+          const summary = await processWithLLM(content, {
+            ...active,
+            llmPrompt: SESSION_SUMMARY_PROMPT,
+          });
+          // The summary goes on top, under the title
+          const [title, ...rest] = content.split("\n\n");
+          await Deno.writeTextFile(
+            session.path,
+            [title, `## Summary\n\n${summary.trim()}`, "## Dictations", ...rest]
+              .join("\n\n")
+          );
+        }
+      }
+      await notify(`📓 Session ended: ${session.name}`, "low");
+      console.log(`✅ Session saved to ${session.path}`);
+      break;
+    }
+    case "status":
+      console.log(
+        session
+          ? `📓 "${session.name}" since ${session.startedAt} → ${session.path}`
+          : "No session running"
+      );
+      break;
+    default:
+      console.error(
+        "Usage: typr session [start [name]|end [--summarize]|status]"
+      );
+      Deno.exit(1);
+  }
+}
+
+// Results that couldn't be typed (focus moved away) wait here until
+// `typr flush` types them into whatever is focused
+const pendingSchema = z.array(
+  z.object({ timestamp: z.string(), app: z.string(), text: z.string() })
+);
+
+type PendingResult = z.infer<typeof pendingSchema>[number];
+
+async function loadPending(): Promise<PendingResult[]> {
+  try {
+    return pendingSchema.parse(
+      JSON.parse(await Deno.readTextFile(PENDING_FILE))
+    );
+  } catch {
+    return [];
+  }
+}
+
+async function savePending(pending: PendingResult[]): Promise<void> {
+  await Deno.writeTextFile(PENDING_FILE, JSON.stringify(pending, null, 2));
+}
+
+// Returns how many results are now pending
+async function holdResult(text: string, app: string): Promise<number> {
+  const pending = await loadPending();
+  pending.push({ timestamp: new Date().toISOString(), app, text });
+  await savePending(pending);
+  return pending.length;
+}
+
+// The recording waiting for `typr review`, with the app it was meant for
+const reviewSchema = z.object({
+  audioPath: z.string(),
+  app: z.string(),
+  recordedAt: z.string(),
+});
+
+type Review = z.infer<typeof reviewSchema>;
+
+async function loadReview(): Promise<Review | null> {
+  try {
+    return reviewSchema.parse(JSON.parse(await Deno.readTextFile(REVIEW_FILE)));
+  } catch {
+    return null;
+  }
+}
+
+// Dropping the review deletes its recording too
+async function saveReview(review: Review | null): Promise<void> {
+  if (review) {
+    await Deno.writeTextFile(REVIEW_FILE, JSON.stringify(review, null, 2));
+  } else {
+    await Deno.remove(REVIEW_FILE).catch(() => {});
+    await Deno.remove(REVIEW_AUDIO_FILE).catch(() => {});
+  }
+}
+
+// Copy rather than rename, since /tmp is often another filesystem
+async function keepForReview(audioPath: string): Promise<void> {
+  await Deno.copyFile(audioPath, REVIEW_AUDIO_FILE);
+  await Deno.remove(audioPath).catch(() => {});
+}
+
+// Peak level (0-1) of each of `columns` equal slices of the recording
+async function waveformPeaks(path: string, columns: number): Promise<number[]> {
+  const { success, stdout } = await new Deno.Command("ffmpeg", {
+    args: [
+      "-v",
+      "error",
+      "-i",
+      path,
+      "-f",
+      "s16le",
+      "-ac",
+      "1",
+      "-ar",
+      "8000",
+      "-",
+    ],
+    stdout: "piped",
+    stderr: "null",
+  }).output();
+  if (!success) throw new Error(`Couldn't read ${path}`);
+
+  const samples = new Int16Array(
+    stdout.slice().buffer,
+    0,
+    Math.floor(stdout.byteLength / 2)
+  );
+  const size = Math.max(1, Math.ceil(samples.length / columns));
+  const peaks: number[] = [];
+  for (let start = 0; start < samples.length; start += size) {
+    let peak = 0;
+    const end = Math.min(start + size, samples.length);
+    for (let i = start; i < end; i++) {
+      peak = Math.max(peak, Math.abs(samples[i]));
+    }
+    peaks.push(peak / 32768);
+  }
+  return peaks;
+}
+
+const WAVEFORM_BARS = "▁▂▃▄▅▆▇█";
+
+// One row of bars on a 60 dB scale, so quiet speech still shows, and a
+// ruler with the time at each quarter
+async function printWaveform(path: string): Promise<number> {
+  let columns = 80;
+  try {
+    columns = Deno.consoleSize().columns;
+  } catch {
+    // Not a terminal (piped output)
+  }
+  columns = Math.max(20, columns - 1);
+  const duration = await audioDuration(path);
+  const peaks = await waveformPeaks(path, columns);
+
+  const bars = peaks.map((peak) => {
+    const level = peak > 0 ? (20 * Math.log10(peak) + 60) / 60 : 0;
+    const index = Math.round(Math.min(1, Math.max(0, level)) * 7);
+    return WAVEFORM_BARS[index];
+  });
+  const ruler = Array<string>(columns).fill(" ");
+  const total = `${duration.toFixed(1)}s`;
+  ruler.splice(columns - total.length, total.length, ...total);
+  let free = 0;
+  for (let quarter = 0; quarter < 4; quarter++) {
+    const label = `${((duration * quarter) / 4).toFixed(1)}s`;
+    const at = Math.round((columns * quarter) / 4);
+    if (at < free || at + label.length >= columns - total.length) continue;
+    ruler.splice(at, label.length, ...label);
+    free = at + label.length + 1;
+  }
+  console.log(bars.join(""));
+  console.log(ruler.join(""));
+  return duration;
+}
+
+// Cut the kept recording down to start..end seconds
+async function trimRecording(
+  review: Review,
+  start: number,
+  end: number
+): Promise<void> {
+  const { audioPath } = review;
+  const trimmedPath = `/tmp/typr-trimmed-${Date.now()}.wav`;
+  const { success } = await new Deno.Command("ffmpeg", {
+    args: [
+      "-i",
+      audioPath,
+      "-ss",
+      String(start),
+      "-to",
+      String(end),
+      "-y",
+      trimmedPath,
+    ],
+    stdout: "null",
+    stderr: "null",
+  }).output();
+  if (!success) throw new Error(`Couldn't trim ${audioPath}`);
+  await keepForReview(trimmedPath);
+  review.audioPath = REVIEW_AUDIO_FILE;
+  await saveReview(review);
+}
+
+async function sendReview(review: Review): Promise<void> {
+  console.log("🔄 Transcribing...");
+  const result = await processAudioFile(review.audioPath);
+  await saveReview(null);
+  if (result.handled) return;
+
+  // The terminal has focus now, so hand the text over rather than type it
+  await copyToClipboard(result.openaiResponse);
+  const count = await holdResult(result.openaiResponse, review.app);
+  console.log(result.openaiResponse);
+  console.log(`📋 Copied to clipboard (${count} pending, typr flush types it)`);
+}
+
+// typr review: look at the kept recording, trim dead air or a false start,
+// then send it. The subcommands do one step each, for scripts.
+async function reviewCommand(
+  action?: string,
+  values: string[] = []
+): Promise<void> {
+  const review = await loadReview();
+  if (!review) {
+    console.log("No recording waiting for review");
+    return;
+  }
+  if (!(await exists(review.audioPath))) {
+    await saveReview(null);
+    console.error(`❌ The kept recording ${review.audioPath} is gone`);
+    Deno.exit(1);
+  }
+
+  switch (action) {
+    case undefined:
+      break;
+    case "waveform":
+      await printWaveform(review.audioPath);
+      return;
+    case "trim": {
+      const [start, end] = values.map(Number);
+      if (!(start >= 0) || !(end > start)) {
+        console.error("Usage: typr review trim <start> <end> (seconds)");
+        Deno.exit(1);
+      }
+      await trimRecording(review, start, end);
+      await printWaveform(review.audioPath);
+      return;
+    }
+    case "send":
+      await sendReview(review);
+      return;
+    case "discard":
+      await saveReview(null);
+      console.log("🗑️ Recording discarded");
+      return;
+    default:
+      console.error(
+        "Usage: typr review [waveform|trim <start> <end>|send|discard]"
+      );
+      Deno.exit(1);
+  }
+
+  const duration = await printWaveform(review.audioPath);
+  const start = Number(prompt("Start at (seconds) [0]:") || 0);
+  const end = Number(
+    prompt(`End at (seconds) [${duration.toFixed(1)}]:`) || duration
+  );
+  if (!(start >= 0) || !(end > start)) {
+    console.error("❌ The end has to come after the start");
+    Deno.exit(1);
+  }
+  if (start > 0 || end < duration) {
+    await trimRecording(review, start, end);
+    await printWaveform(review.audioPath);
+  }
+  if (!confirm("Send for transcription?")) {
+    console.log("Kept for later - typr review discard to drop it");
+    return;
+  }
+  await sendReview(review);
+}
+
+async function pendingCommand(action?: string): Promise<void> {
+  const pending = await loadPending();
+  switch (action) {
+    case undefined:
+      if (pending.length === 0) {
+        console.log("Nothing pending");
+        return;
+      }
+      for (const { timestamp, app, text } of pending) {
+        console.log(`[${timestamp}] (${app || "unknown"}) ${text}`);
+      }
+      break;
+    case "count":
+      console.log(pending.length);
+      break;
+    case "clear":
+      await savePending([]);
+      console.log(`🗑️ Discarded ${pending.length} pending result(s)`);
+      break;
+    default:
+      console.error("Usage: typr pending [count|clear]");
+      Deno.exit(1);
+  }
+}
+
+async function flushPending(): Promise<void> {
+  const pending = await loadPending();
+  if (pending.length === 0) {
+    await notify("Nothing pending", "low");
+    return;
+  }
+  const config = await loadActiveSettings();
+  // Clear first so a second flush can't type the same results twice
+  await savePending([]);
+  await typeText(pending.map(({ text }) => text).join(" "), config);
+  await notify(`✅ Typed ${pending.length} pending result(s)`, "low");
+}
+
+async function appendToInbox(text: string, config: Settings): Promise<void> {
+  const path = expandHome(config.inboxFile);
+  // Local "YYYY-MM-DD HH:MM", one line per capture
+  const stamp = new Date().toLocaleString("sv-SE").slice(0, 16);
+  const line = `- ${stamp} ${text.replace(/\n/g, " ")}\n`;
+  await Deno.writeTextFile(path, line, { append: true });
+  await logToFile("INFO", `📥 Appended to ${path}`);
+  await notify(`📥 Saved to inbox: ${text.slice(0, 80)}`, "low");
+}
+
+// A long dictation finished in the background: by now focus is somewhere
+// else entirely, so the result goes to a file instead of being typed
+async function saveBackgroundResult(
+  text: string,
+  config: Settings
+): Promise<void> {
+  const dir = expandHome(config.backgroundDir);
+  await Deno.mkdir(dir, { recursive: true });
+  const stamp = new Date().toLocaleString("sv-SE").replace(/[ :]/g, "-");
+  const path = join(dir, `${stamp}.md`);
+  await Deno.writeTextFile(path, text + "\n");
+  await logToFile("INFO", `📼 Saved long dictation to ${path}`);
+  await notify(`✅ Long dictation ready: ${path}`);
+}
+
+// Type the final text into the target window (or hand it over if unsafe)
+async function deliverResult(
+  text: string,
+  config: Settings,
+  focusedWindow: FocusedWindow | null,
+  email: EmailDraft | null = null
+): Promise<void> {
+  // A prefilled draft in the default mail app, ready to send
+  if (email && config.emailOutput === "mailto") {
+    const params = new URLSearchParams({
+      subject: email.subject,
+      body: email.body,
+    });
+    // Mail clients expect %20 rather than + for spaces
+    const query = params.toString().replace(/\+/g, "%20");
+    await openPath(`mailto:${encodeURIComponent(email.to)}?${query}`);
+    await notify(`✉️ Draft created: ${email.subject || "(no subject)"}`, "low");
+    return;
+  }
+
+  // Quick capture never touches the focused window
+  if (config.output === "inbox") {
+    await appendToInbox(text, config);
+    return;
+  }
+
+  if (config.output === "form" && (await fillForm(text, config))) {
+    return;
+  }
+
+  // No OS-level typing at all, for when injection is blocked
+  if (config.output === "scratchpad") {
+    const path = expandHome(config.scratchpadFile);
+    await Deno.writeTextFile(path, text + "\n", { append: true });
+    await notify("🗒️ Added to scratchpad", "low");
+    return;
+  }
+
+  // Never type into Typr's own files (settings, scratchpad, a correction)
+  // open in an editor
+  const current = await getFocusedWindow();
+  if (current && isTyprWindow(current, config)) {
+    await copyToClipboard(text);
+    const count = await holdResult(text, current.app);
+    await logToFile(
+      "INFO",
+      `Not typing into Typr's own window: ${current.title}`
+    );
+    await notify(
+      `📋 That's a Typr file - result copied to clipboard (${count} pending)`
+    );
+    return;
+  }
+
+  if (!(await ensureFocus(focusedWindow, config))) {
+    await copyToClipboard(text);
+    const count = await holdResult(text, focusedWindow?.app ?? "");
+    await notify(
+      `📋 Focus moved away from ${focusedWindow?.app} - result copied to clipboard (${count} pending, typr flush to type)`
+    );
+    return;
+  }
+
+  await notify("✅ Typing result...", "low");
+  // Every piece but the last is sent as its own message
+  const parts = outputParts(text, config);
+  const sendKeys = postTypeKeysFor(config, focusedWindow?.app);
+  let diverted = false;
+  for (const [index, part] of parts.entries()) {
+    if (!(await typeText(part, config))) {
+      // Each diverted part would replace the last on the clipboard, so the
+      // rest goes over in one copy, with no keys pressed between parts
+      if (parts.length > 1) {
+        await copyToClipboard(
+          withoutKeyCommands(parts.slice(index).join("\n"), config)
+        );
+      }
+      diverted = true;
+      break;
+    }
+    const last = index === parts.length - 1;
+    await pressPostTypeKeys(last || sendKeys.length ? sendKeys : ["enter"]);
+    // Chat apps drop or merge messages sent too quickly
+    if (!last && config.messageDelayMs > 0) {
+      await new Promise((resolve) =>
+        setTimeout(resolve, config.messageDelayMs)
+      );
+    }
+  }
+  if (parts.length > 1 && !diverted) {
+    await notify(`✂️ Sent in ${parts.length} parts`, "low");
+  }
+  await notify("🎯 Done!", "low");
+
+  if (config.readBack !== "off") {
+    await speakText(text, config);
+  }
+}
+
+// Audio uploaded from a phone: normalise it and run the usual pipeline,
+// typing into whatever is focused on this machine
+async function handleRemoteAudio(request: Request): Promise<Response> {
+  const stamp = new Date().toISOString().replace(/[:.]/g, "-");
+  const uploadPath = `/tmp/typr-remote-${stamp}.upload`;
+  const audioPath = `/tmp/typr-recording-${stamp}.wav`;
+  let receivedAt = Date.now();
+
+  try {
+    const upload = new Uint8Array(await request.arrayBuffer());
+    await Deno.writeFile(uploadPath, upload);
+    await convertToWav(uploadPath, audioPath);
+    await logToFile("INFO", `📱 Received remote audio ${audioPath}`);
+
+    receivedAt = Date.now();
+    const result = await processAudioFile(audioPath);
+    const config = await loadActiveSettings();
+    if (!result.handled) {
+      await deliverResult(result.openaiResponse, config, null, result.email);
+    }
+    await recordRunMetrics(result, config, receivedAt, audioPath);
+    return Response.json({ text: result.openaiResponse });
+  } catch (error) {
+    await logToFile("ERROR", "Failed to process remote audio", error);
+    await recordRunFailure(error, receivedAt, audioPath);
+    await playFailureCue(classifyFailure(error));
+    return Response.json({ error: String(error) }, { status: 500 });
+  } finally {
+    await Deno.remove(uploadPath).catch(() => {});
+  }
+}
+
+// A short countdown for pointer/menu triggers, leaving time to click into
+// the target field. False when the user toggled again to cancel.
+async function startCountdown(seconds: number): Promise<boolean> {
+  for (let remaining = seconds; remaining > 0; remaining--) {
+    await notify(`⏳ Recording in ${remaining}...`, "low");
+    await playTone(880, 0.05).catch(() => playBeep());
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    if (await getState("stopRequested")) return false;
+  }
+  return true;
+}
+
+// macOS only: Linux has no way to read the keyboard's modifier state
+// without root, so bind a Shift variant of the shortcut to the flags instead
+async function isShiftHeld(): Promise<boolean> {
+  if (Deno.build.os !== "darwin") return false;
+  try {
+    const { stdout } = await new Deno.Command("osascript", {
+      args: [
+        "-l",
+        "JavaScript",
+        "-e",
+        // NSEventModifierFlagShift
+        "ObjC.import('AppKit'); ($.NSEvent.modifierFlags & (1 << 17)) !== 0",
+      ],
+      stdout: "piped",
+      stderr: "null",
+    }).output();
+    return new TextDecoder().decode(stdout).trim() === "true";
+  } catch {
+    return false;
+  }
+}
+
+// The stopping toggle decides the options and leaves them in the state file
+// for the recording process, which runs the pipeline
+async function requestStopOptions(
+  flags: Partial<DictationOptions>
+): Promise<void> {
+  const options = { ...DEFAULT_DICTATION_OPTIONS, ...flags };
+  const { shiftAction } = await loadSettings();
+  if (shiftAction !== "none" && (await isShiftHeld())) {
+    if (shiftAction === "copy-only") options.copyOnly = true;
+    if (shiftAction === "skip-llm") options.skipLLM = true;
+  }
+  if (options.copyOnly || options.skipLLM) {
+    await logToFile("INFO", `Stop options: ${JSON.stringify(options)}`);
+    await setState("stopOptions", JSON.stringify(options));
+  }
+}
+
+async function takeStopOptions(): Promise<DictationOptions> {
+  const saved = await getState("stopOptions");
+  if (!saved) return DEFAULT_DICTATION_OPTIONS;
+  try {
+    return { ...DEFAULT_DICTATION_OPTIONS, ...JSON.parse(saved) };
+  } catch {
+    return DEFAULT_DICTATION_OPTIONS;
+  }
+}
+
+async function handleToggleRecording(
+  countdown = 0,
+  flags: Partial<DictationOptions> = {}
+): Promise<void> {
+  // Check if there's already a recording (ffmpeg) process running
+  const ffmpegPid = await getState("ffmpegPid");
+
+  if (ffmpegPid) {
+    // Verify the process is actually running
+    try {
+      const command = new Deno.Command("ps", { args: ["-p", ffmpegPid] });
+      const result = await command.output();
+      if (!result.success) {
+        // Process doesn't exist, clear stale state
+        await logToFile("INFO", `Stale PID ${ffmpegPid} found, clearing state`);
+        await clearState();
+      }
+    } catch {
+      // ps command failed, assume process doesn't exist
+      await logToFile(
+        "INFO",
+        `Could not check PID ${ffmpegPid}, clearing state`
+      );
+      await clearState();
+      // Continue to start new recording
+    }
+  }
+
+  if (ffmpegPid && (await getState("ffmpegPid"))) {
+    // Re-check after potential cleanup
+    // Second toggle: Kill the ffmpeg process directly
+    await requestStopOptions(flags);
+    await notify("⏹️ Stopping recording...", "low");
+    await playDoubleBeep();
+
+    // Keep recording a moment after the key is released so the last
+    // syllable isn't clipped
+    const { releaseGraceMs } = await loadSettings();
+    if (releaseGraceMs > 0) {
+      await new Promise((resolve) => setTimeout(resolve, releaseGraceMs));
+    }
+    try {
+      await logToFile("INFO", `Sending SIGTERM to ffmpeg process ${ffmpegPid}`);
+      Deno.kill(parseInt(ffmpegPid), "SIGTERM");
+    } catch {
+      // Process may have already ended, which is fine
+      await logToFile(
+        "INFO",
+        `Process ${ffmpegPid} already ended or not found`
+      );
+    }
+    return;
+  }
+
+  // Only one recording waits for review at a time; a second would replace
+  // the first
+  if ((await loadSettings()).reviewRecordings && (await loadReview())) {
+    await notify(
+      "🎧 A recording is waiting - typr review send or discard it first"
+    );
+    return;
+  }
+
+  // First toggle: Start recording process, unless another instance (a
+  // toggle that's still starting up, or `typr listen`) already is
+  if (!(await acquireRecordingLock())) {
+    // A quick press/release can land before the first toggle has started
+    // ffmpeg; queue the stop rather than dropping it
+    if ((await getPhase()) === "starting") {
+      await logToFile("INFO", "Recording still starting, queueing stop");
+      await requestStopOptions(flags);
+      await setState("stopRequested", "true");
+      await notify("⏹️ Stopping recording...", "low");
+      return;
+    }
+    await logToFile("INFO", "Another instance holds the recording lock");
+    await notify("⏳ Typr is already recording in another process", "low");
+    return;
+  }
+  await stopPlayback();
+  await clearState();
+  await setPhase("starting");
+
+  if (countdown > 0 && !(await startCountdown(countdown))) {
+    await notify("🚫 Recording cancelled", "low");
+    await releaseRecordingLock();
+    await setPhase("idle");
+    return;
+  }
+
+  // Remember where the text should go in case focus moves while processing
+  const focusedWindow = await getFocusedWindow();
+  const settings = await loadActiveSettings();
+
+  if (settings.liveDictation) {
+    try {
+      // Enter after text that went to the clipboard could submit whatever
+      // secure field diverted it
+      let typedAll = true;
+      await runLiveDictation(
+        settings,
+        async (text) => {
+          if (!(await typeText(text + " ", settings))) typedAll = false;
+          await publishEvent(settings, {
+            type: "transcript",
+            final: true,
+            text,
+            timestamp: new Date().toISOString(),
+          });
+        },
+        (delta) =>
+          publishEvent(settings, {
+            type: "transcript",
+            final: false,
+            text: delta,
+            timestamp: new Date().toISOString(),
+          })
+      );
+      if (typedAll) {
+        await pressPostTypeKeys(postTypeKeysFor(settings, focusedWindow?.app));
+      }
+    } catch (error) {
+      await logToFile("ERROR", "Live dictation failed", error);
+      await playFailureCue(classifyFailure(error));
+      await notify("❌ Live dictation failed", "critical");
+      await releaseRecordingLock();
+      await setPhase("error");
+      return;
+    }
+    await releaseRecordingLock();
+    await setPhase("idle");
+    return;
+  }
+
+  // Read the screen while the user is looking at what they'll talk about
+  const screenTerms = captureScreenTerms(settings);
+  if (settings.warmUp) warmUp(settings);
+
+  let recording: Recording | null;
+  try {
+    recording = await startRecording(settings);
+  } finally {
+    await releaseRecordingLock();
+  }
+  if (!recording) {
+    await logToFile("ERROR", "Failed to start recording");
+    await setPhase("error");
+    return;
+  }
+
+  // Recording completed (ffmpeg terminated), process the audio
+  const stoppedAt = Date.now();
+  const options = await takeStopOptions();
+  await clearState(); // Clear the ffmpeg PID
+
+  if (settings.reviewRecordings) {
+    await keepForReview(recording.audioPath);
+    await saveReview({
+      audioPath: REVIEW_AUDIO_FILE,
+      app: focusedWindow?.app ?? "",
+      recordedAt: new Date().toISOString(),
+    });
+    await setPhase("idle");
+    await notify("🎧 Recording kept - run typr review to trim and send it");
+    return;
+  }
+  await setPhase("processing");
+  await logToFile("INFO", "🔄 Processing recorded audio...");
+
+  // Long recordings take minutes to process; hand the hotkey back straight
+  // away and save the result rather than typing it wherever focus is then
+  const background =
+    settings.backgroundAfterSeconds > 0 &&
+    (await audioDuration(recording.audioPath)) >
+      settings.backgroundAfterSeconds;
+  if (background) {
+    await setPhase("idle");
+    await notify("📼 Long recording - processing in the background");
+  }
+
+  try {
+    const streamedTranscription = await recording.streamed?.finish();
+    const result = await processAudioFile(
+      recording.audioPath,
+      streamedTranscription,
+      await screenTerms,
+      options
+    );
+    const config = await loadActiveSettings();
+    if (!result.handled && background) {
+      await saveBackgroundResult(result.openaiResponse, config);
+    } else if (!result.handled && options.copyOnly) {
+      await copyToClipboard(result.openaiResponse);
+      await notify("📋 Copied to clipboard", "low");
+    } else if (!result.handled) {
+      await deliverResult(
+        result.openaiResponse,
+        config,
+        focusedWindow,
+        result.email
+      );
+    }
+    await recordRunMetrics(result, config, stoppedAt, recording.audioPath);
+  } catch (error) {
+    await logToFile("ERROR", "Failed to process audio", error);
+    await recordRunFailure(error, stoppedAt, recording.audioPath);
+    await playFailureCue(classifyFailure(error));
+    await notify("❌ Processing failed", "critical");
+    if ((await getPhase()) === "processing") await setPhase("error");
+    return;
+  }
+  // A new recording may have started meanwhile; leave its state alone
+  if ((await getPhase()) === "processing") await setPhase("idle");
+}
+
+// First-run onboarding: real checks before the hotkey is worth binding
+async function commandWorks(name: string, args: string[]): Promise<boolean> {
+  try {
+    const { success } = await new Deno.Command(name, {
+      args,
+      stdout: "null",
+      stderr: "null",
+    }).output();
+    return success;
+  } catch {
+    return false;
+  }
+}
+
+// Accessibility permission on macOS, an installed typing tool on Linux
+async function canSimulateTyping(): Promise<boolean> {
+  if (Deno.build.os === "darwin") {
+    return await commandWorks("osascript", [
+      "-e",
+      'tell application "System Events" to keystroke ""',
+    ]);
+  }
+  return (
+    (await commandWorks("xdotool", ["version"])) ||
+    (await commandWorks("wtype", ["-h"]))
+  );
+}
+
+async function runSetup(): Promise<void> {
+  console.log("👋 Welcome to Typr! Let's make sure everything works.\n");
+
+  // 1. Recording dependencies and microphone permission
+  if (!(await commandWorks("ffmpeg", ["-version"]))) {
+    console.log("❌ ffmpeg not found - install it and run `typr setup` again");
+    Deno.exit(1);
+  }
+  console.log("✅ ffmpeg installed");
+
+  console.log("🎙️  Checking microphone access (you may see a permission prompt)");
+  if (!(await recordSample("/tmp/typr-setup-mic.wav", 1))) {
+    console.log(
+      Deno.build.os === "darwin"
+        ? "❌ Microphone blocked - allow your terminal under System Settings > Privacy & Security > Microphone"
+        : "❌ Could not record - check that PulseAudio/PipeWire has a default source"
+    );
+    Deno.exit(1);
+  }
+  console.log("✅ Microphone works");
+
+  // 2. Typing permission
+  if (!(await canSimulateTyping())) {
+    console.log(
+      Deno.build.os === "darwin"
+        ? "❌ Typing blocked - allow your terminal under System Settings > Privacy & Security > Accessibility"
+        : "❌ Install xdotool (X11) or wtype (Wayland) so Typr can type"
+    );
+    Deno.exit(1);
+  }
+  console.log("✅ Typing allowed");
+
+  // 3. Local vs cloud transcription
+  const config = await loadSettings();
+  const choice = prompt(
+    "\n☁️  Transcribe in the cloud (OpenAI) or locally with Whisper? [cloud/local]",
+    config.useLocalWhisper ? "local" : "cloud"
+  );
+  if (choice?.toLowerCase().startsWith("l")) {
+    if (!(await isWhisperAvailable(config))) {
+      console.log(
+        `❌ ${localWhisperBinary(config)} not found - run \`pip install openai-whisper\` or set localWhisperPath`
+      );
+      Deno.exit(1);
+    }
+    config.useLocalWhisper = true;
+  } else {
+    config.useLocalWhisper = false;
+    if (getOpenAIKeys(config).length === 0) {
+      config.openAIKey = prompt("🔑 OpenAI API key:")?.trim() ?? "";
+    }
+  }
+  await saveSettings(config);
+
+  // 4. Test dictation into the terminal instead of another app
+  prompt("\n🧪 Press Enter, then say a sentence (recording for 5 seconds)");
+  const testPath = "/tmp/typr-setup-test.wav";
+  await recordSample(testPath, 5);
+  try {
+    const result = await processAudioFile(testPath);
+    console.log(`\n📝 You said: ${result.openaiResponse}\n`);
+  } catch (error) {
+    console.log(`❌ Test dictation failed: ${error}`);
+    console.log("   Fix the problem above and run `typr setup` again.");
+    Deno.exit(1);
+  }
+
+  // 5. Only now is it worth binding the hotkey
+  console.log("🎉 Everything works! Last step: bind the shortcut.\n");
+  showShortcutInstructions();
+}
+
+// Suggest a noise gate threshold just above the room's ambient noise
+async function calibrateNoiseGate(): Promise<void> {
+  prompt("🤫 Press Enter, then stay quiet for 3 seconds");
+  const samplePath = "/tmp/typr-calibration.wav";
+  if (!(await recordSample(samplePath, 3))) {
+    console.error("❌ Could not record from the microphone");
+    Deno.exit(1);
+  }
+
+  const { mean, max } = await measureVolume(samplePath);
+  if (Number.isNaN(max)) {
+    console.error("❌ Could not measure the recording volume");
+    Deno.exit(1);
+  }
+
+  const suggested = Math.min(Math.round(max + 6), -10);
+  console.log(`📈 Ambient noise: mean ${mean} dB, peak ${max} dB`);
+  if (confirm(`Set noiseGateDb to ${suggested}?`)) {
+    const config = await loadSettings();
+    await saveSettings({ ...config, noiseGateDb: suggested });
+    console.log(`✅ Noise gate set to ${suggested} dB`);
+  }
+}
+
+// Validate the local Whisper settings without transcribing anything
+async function checkLocalWhisper(
+  config: Settings
+): Promise<{ ok: boolean; detail: string }> {
+  const binary = localWhisperBinary(config);
+  if (!hasFeature("local-whisper")) {
+    return { ok: false, detail: "not included in this build of Typr" };
+  }
+  if (!(await isWhisperAvailable(config))) {
+    return { ok: false, detail: `${binary} not found or not runnable` };
+  }
+
+  // whisper.cpp takes a model file rather than a model name
+  if (config.localWhisperFlavor === "whisper.cpp") {
+    const model = expandHome(config.localWhisperModel);
+    if (!(await exists(model))) {
+      return { ok: false, detail: `model file ${model} not found` };
+    }
+  }
+
+  try {
+    const probe = join(config.localWhisperOutputDir, ".typr-write-test");
+    await Deno.writeTextFile(probe, "");
+    await Deno.remove(probe);
+  } catch {
+    return {
+      ok: false,
+      detail: `output directory ${config.localWhisperOutputDir} is not writable`,
+    };
+  }
+
+  return {
+    ok: true,
+    detail: `${binary} with model ${config.localWhisperModel}`,
+  };
+}
+
+// Machine-readable snapshot for status bars and scripts
+async function showStatus(): Promise<void> {
+  const config = await loadActiveSettings();
+
+  const ffmpegPid = await getState("ffmpegPid");
+  const recording =
+    !!ffmpegPid && (await commandWorks("ps", ["-p", ffmpegPid]));
+
+  let lastError: string | null = null;
+  if (await exists(LOG_FILE)) {
+    const lines = (await Deno.readTextFile(LOG_FILE)).trimEnd().split("\n");
+    lastError = lines.findLast((line) => line.includes("] ERROR: ")) ?? null;
+  }
+
+  const status = {
+    phase: await getPhase(),
+    pending: (await loadPending()).length,
+    recording,
+    audioPath: recording ? await getState("audioPath") : null,
+    device: (await microphoneInputArgs(config, false)).join(" "),
+    mode: config.activeMode,
+    paused: config.paused,
+    transcriptionProvider: config.useLocalWhisper
+      ? `local (${config.localWhisperFlavor}), then ${config.transcriptionProvider}`
+      : config.transcriptionProvider,
+    transcriptionConfigured: isTranscriptionConfigured(config),
+    llmProvider: isLLMConfigured(config) ? config.llmProvider : null,
+    skipLLM: config.skipLLM,
+    liveDictation: config.liveDictation,
+    lastError,
+    permissions: {
+      ffmpeg: await commandWorks("ffmpeg", ["-version"]),
+      typing: await canSimulateTyping(),
+      secureInput: await isSecureInputActive(),
+    },
+  };
+  console.log(JSON.stringify(status, null, 2));
+}
+
+// Print the exact transcription prompt the next dictation will use
+async function previewPrompt(): Promise<void> {
+  const config = await loadActiveSettings();
+  console.log(
+    `📝 Whisper prompt (mode: ${config.activeMode}, language: ${config.language})\n`
+  );
+  console.log(config.whisperPrompt || "(empty)");
+
+  // Whisper only conditions on the tail of a long prompt (~224 tokens)
+  const words = config.whisperPrompt.split(/\s+/).filter(Boolean).length;
+  console.log(`\n${config.whisperPrompt.length} characters, ~${words} words`);
+  if (words > 150) {
+    console.log(
+      "⚠️  Whisper only uses roughly the last 224 tokens of the prompt"
+    );
+  }
+  if (config.whisperPrompt.includes(DEFAULT_WHISPER_PROMPT)) {
+    console.log(
+      "⚠️  whisperPrompt still has the XXX/YYY/ZZZ template placeholders"
+    );
+  }
+}
+
+// CLI Commands
+async function showConfig(check = false): Promise<void> {
+  if (check) {
+    const result = await validateSettingsFile(true);
+    if (result.success) {
+      console.log(`✅ ${SETTINGS_FILE} is valid`);
+      return;
+    }
+    console.error(`❌ ${SETTINGS_FILE} has problems:`);
+    for (const issue of result.issues) console.error(`  - ${issue}`);
+    Deno.exit(1);
+  }
+
+  const config = await loadSettings();
+  console.log(config);
+  if (BUILD_FEATURES !== null) {
+    const features = BUILD_FEATURES.join(", ") || "no optional features";
+    console.log(`Built with: ${features}`);
+  }
+}
+
+async function checkSetup(): Promise<void> {
+  const config = await loadSettings();
+
+  const provider = await checkTranscriptionProvider(config);
+  console.log(
+    `${provider.ok ? "✅" : "❌"} Transcription (${
+      config.transcriptionProvider
+    }): ${provider.detail}`
+  );
+
+  let whisperOk = true;
+  if (config.useLocalWhisper) {
+    const whisper = await checkLocalWhisper(config);
+    whisperOk = whisper.ok;
+    console.log(
+      `${whisper.ok ? "✅" : "❌"} Local Whisper (${
+        config.localWhisperFlavor
+      }): ${whisper.detail}`
+    );
+  }
+
+  if (!provider.ok || !whisperOk) {
+    Deno.exit(1);
+  }
+}
+
+async function showHistory(count = 10): Promise<void> {
+  const history = await loadHistory();
+  for (const entry of history.slice(-count)) {
+    const failed = entry.llmStatus === "failed" ? " ⚠️ edit failed" : "";
+    const retried = entry.attempt > 1 ? " 🔁 retried" : "";
+    const source = entry.source ? ` 📁 ${entry.source}` : "";
+    console.log(
+      `${entry.id.slice(0, 8)} [${entry.timestamp}] (${
+        entry.mode
+      })${failed}${retried}${source} ${entry.text}`
+    );
+  }
+}
+
+// Run the editing step again on a stored transcript (the latest by default)
+async function reeditHistoryEntry(id?: string): Promise<void> {
+  const history = await loadHistory();
+  const entry = id
+    ? history.find((entry) => entry.id.startsWith(id))
+    : history[history.length - 1];
+  if (!entry) {
+    console.error(id ? `❌ No history entry ${id}` : "❌ History is empty");
+    Deno.exit(1);
   }
 
-  const data = await response.json();
-  return data.choices[0]?.message?.content?.trim() || transcription;
+  // Edit with the mode the dictation was made in
+  const config = await loadActiveSettings(entry.mode);
+  if (!isLLMConfigured(config)) {
+    console.error(`❌ ${config.llmProvider} is not configured`);
+    Deno.exit(1);
+  }
+
+  let response = await processWithLLM(entry.transcription, config);
+  if (config.validateLLMResponse) {
+    response = cleanLLMResponse(response);
+    const problem = llmResponseProblem(response, entry.transcription);
+    if (problem) {
+      console.error(`⚠️ The edit didn't match the dictation: ${problem}`);
+      console.error("   Keeping the raw transcript");
+      response = entry.transcription;
+    }
+  }
+  const edited = filterProfanity(formatOutput(response, config), config);
+  entry.text = edited;
+  entry.llmStatus = "ok";
+  await saveHistory(history, config.historyLimit);
+
+  await copyToClipboard(edited);
+  console.log(edited);
+  await notify("✏️ Re-edited dictation copied to clipboard", "low");
 }
 
-// Text typing simulation
-async function typeText(text: string): Promise<void> {
-  await logToFile("INFO", `⌨️  Typing: ${text}`);
+// Manage the prompt library and try prompts on past dictations
+async function promptsCommand(
+  action?: string,
+  values: string[] = []
+): Promise<void> {
+  const library = await loadPromptLibrary();
+  const [reference] = values;
+  const name = reference?.split("@")[0];
 
-  try {
-    switch (Deno.build.os) {
-      case "darwin":
-        await typeMacOS(text);
-        break;
-      case "linux":
-        await typeLinux(text);
-        break;
-      default:
-        throw new Error(
-          `Unsupported OS: ${Deno.build.os}. Only Linux and macOS are supported.`
+  switch (action) {
+    case undefined: {
+      const settings = await loadSettings();
+      // Which settings and modes refer to each prompt
+      const users = (prompt: string) =>
+        [
+          ...(settings.prompt.split("@")[0] === prompt ? ["settings"] : []),
+          ...Object.entries(settings.modes)
+            .filter(([, mode]) => mode.prompt?.split("@")[0] === prompt)
+            .map(([mode]) => `mode ${mode}`),
+        ].join(", ");
+      const names = Object.keys(library);
+      if (names.length === 0) {
+        console.log("No saved prompts (typr prompts edit <name> adds one)");
+      }
+      for (const prompt of names) {
+        const latest = library[prompt][library[prompt].length - 1];
+        const used = users(prompt);
+        console.log(
+          `${prompt} (v${latest.version}, ${latest.savedAt})${
+            used ? ` used by ${used}` : ""
+          }`
         );
+      }
+      return;
     }
-  } catch (error) {
-    await logToFile("ERROR", "Failed to type text", error);
+    case "show": {
+      const found = reference ? findPrompt(library, reference) : null;
+      if (!found) {
+        console.error(`❌ No prompt ${reference ?? ""}`);
+        Deno.exit(1);
+      }
+      console.log(found.text);
+      return;
+    }
+    case "history":
+      if (!name || !library[name]) {
+        console.error(`❌ No prompt ${name ?? ""}`);
+        Deno.exit(1);
+      }
+      for (const { version, savedAt, text } of library[name]) {
+        const firstLine = text.split("\n")[0].slice(0, 60);
+        console.log(`v${version} [${savedAt}] ${firstLine}`);
+      }
+      return;
+    case "edit":
+    case "revert": {
+      if (!name || (action === "revert" && !reference.includes("@"))) {
+        console.error(
+          action === "revert"
+            ? "Usage: typr prompts revert <name>@<version>"
+            : "Usage: typr prompts edit <name>"
+        );
+        Deno.exit(1);
+      }
+      const versions = library[name] ?? [];
+      const latest = versions[versions.length - 1];
+      let text: string | null;
+      if (action === "revert") {
+        text = findPrompt(library, reference)?.text ?? null;
+        if (text === null) {
+          console.error(`❌ No prompt ${reference}`);
+          Deno.exit(1);
+        }
+      } else {
+        // New prompts start from the one in use, so there's something to
+        // change rather than a blank page
+        const config = await loadActiveSettings();
+        text = await editText(
+          latest?.text ?? config.llmPrompt,
+          "typr-prompt-"
+        );
+      }
+      if (!text || text === latest?.text) {
+        console.log("No changes");
+        return;
+      }
+      const version = (latest?.version ?? 0) + 1;
+      library[name] = [
+        ...versions,
+        { version, savedAt: new Date().toISOString(), text },
+      ];
+      await savePromptLibrary(library);
+      console.log(`✅ Saved ${name} v${version}`);
+      return;
+    }
+    case "test": {
+      const found = reference ? findPrompt(library, reference) : null;
+      if (!found) {
+        console.error(
+          reference
+            ? `❌ No prompt ${reference}`
+            : "Usage: typr prompts test <name>[@version] [history-id]"
+        );
+        Deno.exit(1);
+      }
+      const history = await loadHistory();
+      const id = values[1];
+      const entry = id
+        ? history.find((entry) => entry.id.startsWith(id))
+        : history[history.length - 1];
+      if (!entry) {
+        console.error(
+          id ? `❌ No history entry ${id}` : "❌ History is empty"
+        );
+        Deno.exit(1);
+      }
+
+      const config = await loadActiveSettings(entry.mode);
+      if (!isLLMConfigured(config)) {
+        console.error(`❌ ${config.llmProvider} is not configured`);
+        Deno.exit(1);
+      }
+      console.log(`Transcript:\n${entry.transcription}\n`);
+      console.log(`Typed at the time:\n${entry.text}\n`);
+      const response = cleanLLMResponse(
+        await processWithLLM(entry.transcription, {
+          ...config,
+          llmPrompt: found.text,
+        })
+      );
+      console.log(`${reference} v${found.version}:`);
+      console.log(filterProfanity(formatOutput(response, config), config));
+      const problem = llmResponseProblem(response, entry.transcription);
+      if (problem && config.validateLLMResponse) {
+        console.log(`\n⚠️ Would be discarded as not matching: ${problem}`);
+      }
+      return;
+    }
+    default:
+      console.error(
+        "Usage: typr prompts [show|history|edit|revert|test] <name>[@version]"
+      );
+      Deno.exit(1);
   }
 }
 
-async function typeMacOS(text: string): Promise<void> {
-  const script = `tell application "System Events" to keystroke "${text.replace(
-    /"/g,
-    '\\"'
-  )}"`;
-  const command = new Deno.Command("osascript", { args: ["-e", script] });
-  await command.output();
+// Corrections made with `typr correct`, mined for replacement rules
+const correctionsSchema = z.object({
+  corrections: z
+    .array(
+      z.object({
+        timestamp: z.string(),
+        mode: z.string(),
+        from: z.string(),
+        to: z.string(),
+      })
+    )
+    .default([]),
+  // Suggestions the user turned down, as "from → to"
+  dismissed: z.array(z.string()).default([]),
+});
+
+type Corrections = z.infer<typeof correctionsSchema>;
+
+async function loadCorrections(): Promise<Corrections> {
+  try {
+    const data = JSON.parse(await Deno.readTextFile(CORRECTIONS_FILE));
+    return correctionsSchema.parse(data);
+  } catch {
+    return correctionsSchema.parse({});
+  }
 }
 
-async function typeLinux(text: string): Promise<void> {
-  // Use xdotool for Linux
-  const command = new Deno.Command("xdotool", { args: ["type", text] });
-  await command.output();
+async function saveCorrections(corrections: Corrections): Promise<void> {
+  await Deno.writeTextFile(
+    CORRECTIONS_FILE,
+    JSON.stringify(corrections, null, 2)
+  );
 }
 
-// Audio processing pipeline
-async function processAudioFile(
-  audioPath: string
-): Promise<{ transcription: string; openaiResponse: string }> {
-  const config = await loadSettings();
+// Word runs that were replaced between two versions of a text (an LCS diff
+// that ignores case and punctuation). Pure insertions and deletions are
+// style edits rather than misheard words, so they're left out.
+function diffWords(
+  before: string,
+  after: string
+): { from: string; to: string }[] {
+  const a = before.split(/\s+/).filter(Boolean);
+  const b = after.split(/\s+/).filter(Boolean);
+  const key = (word: string) =>
+    word.toLowerCase().replace(/^[^\p{L}\p{N}]+|[^\p{L}\p{N}]+$/gu, "");
 
-  if (!config.openAIKey && !config.useLocalWhisper) {
-    await logToFile(
-      "ERROR",
-      "No OpenAI API key configured and local Whisper not enabled"
-    );
-    throw new Error(
-      "No OpenAI API key configured and local Whisper not enabled"
-    );
+  const lcs = Array.from({ length: a.length + 1 }, () =>
+    new Array<number>(b.length + 1).fill(0)
+  );
+  for (let i = a.length - 1; i >= 0; i--) {
+    for (let j = b.length - 1; j >= 0; j--) {
+      lcs[i][j] =
+        key(a[i]) === key(b[j])
+          ? lcs[i + 1][j + 1] + 1
+          : Math.max(lcs[i + 1][j], lcs[i][j + 1]);
+    }
   }
 
-  let transcription = "";
+  const changes: { from: string; to: string }[] = [];
+  let removed: string[] = [];
+  let added: string[] = [];
+  const flush = () => {
+    if (removed.length && added.length && removed.length <= 4) {
+      const strip = (words: string[]) =>
+        words.join(" ").replace(/^[^\p{L}\p{N}]+|[^\p{L}\p{N}]+$/gu, "");
+      changes.push({ from: strip(removed), to: strip(added) });
+    }
+    removed = [];
+    added = [];
+  };
 
-  // Try local Whisper first if enabled
-  if (config.useLocalWhisper && (await isWhisperAvailable())) {
-    try {
-      transcription = await transcribeWithLocalWhisper(
-        audioPath,
-        config.whisperPrompt
-      );
-    } catch (error) {
-      await logToFile(
-        "ERROR",
-        "Local Whisper failed, falling back to OpenAI",
-        error
-      );
+  let i = 0;
+  let j = 0;
+  while (i < a.length || j < b.length) {
+    if (i < a.length && j < b.length && key(a[i]) === key(b[j])) {
+      flush();
+      i++;
+      j++;
+    } else if (
+      j < b.length &&
+      (i === a.length || lcs[i][j + 1] >= lcs[i + 1][j])
+    ) {
+      added.push(b[j++]);
+    } else {
+      removed.push(a[i++]);
     }
   }
+  flush();
+  return changes;
+}
 
-  // Use OpenAI if local Whisper wasn't used or failed
-  if (!transcription && config.openAIKey) {
-    transcription = await transcribeWithOpenAI(
-      audioPath,
-      config.openAIKey,
-      config.whisperPrompt
-    );
+// Open text in $EDITOR; returns the edited text, or null if the editor failed
+async function editText(text: string, prefix: string): Promise<string | null> {
+  const path = await Deno.makeTempFile({ prefix, suffix: ".txt" });
+  await Deno.writeTextFile(path, text + "\n");
+  const editor = Deno.env.get("VISUAL") || Deno.env.get("EDITOR") || "vi";
+  const { success } = await new Deno.Command("sh", {
+    args: ["-c", `${editor} "$1"`, "sh", path],
+    stdin: "inherit",
+    stdout: "inherit",
+    stderr: "inherit",
+  }).output();
+  const edited = (await Deno.readTextFile(path)).trim();
+  await Deno.remove(path).catch(() => {});
+  return success ? edited : null;
+}
+
+// Fix a dictation in $EDITOR and remember what changed
+async function correctHistoryEntry(id?: string): Promise<void> {
+  const history = await loadHistory();
+  const entry = id
+    ? history.find((entry) => entry.id.startsWith(id))
+    : history[history.length - 1];
+  if (!entry) {
+    console.error(id ? `❌ No history entry ${id}` : "❌ History is empty");
+    Deno.exit(1);
   }
 
-  if (!transcription || transcription.length < 10) {
-    throw new Error("Transcription failed or too short");
+  const corrected = await editText(entry.text, "typr-correct-");
+  if (!corrected || corrected === entry.text) {
+    console.log("No changes");
+    return;
   }
 
-  // Apply simple replacements
-  transcription = transcription.replace(/slap/gi, "\n");
+  const changes = diffWords(entry.text, corrected);
+  const corrections = await loadCorrections();
+  const timestamp = new Date().toISOString();
+  for (const change of changes) {
+    corrections.corrections.push({ timestamp, mode: entry.mode, ...change });
+  }
+  await saveCorrections(corrections);
 
-  // Clean up whitespace
-  transcription = transcription
-    .split("\n")
-    .map((line) => line.trim())
-    .filter((line) => line.length > 0)
-    .join("\n");
+  const config = await loadSettings();
+  entry.text = corrected;
+  await saveHistory(history, config.historyLimit);
+  await copyToClipboard(corrected);
+  console.log(`✅ Saved ${changes.length} correction(s), copied to clipboard`);
+}
 
-  let openaiResponse = transcription;
+type Suggestion = { from: string; to: string; count: number };
 
-  // Process with GPT if "note to the editor" is mentioned
-  if (config.openAIKey) {
-    try {
-      openaiResponse = await processWithGPT(
-        transcription,
-        config.openAIKey,
-        config.llmPrompt
-      );
-    } catch (error) {
-      await logToFile("ERROR", "GPT processing failed", error);
-    }
+// Corrections made at least twice that no replacement rule covers yet
+async function findSuggestions(config: Settings): Promise<Suggestion[]> {
+  const { corrections, dismissed } = await loadCorrections();
+  const counts = new Map<string, Suggestion>();
+  for (const { from, to } of corrections) {
+    const id = `${from.toLowerCase()} → ${to}`;
+    const suggestion = counts.get(id) ?? { from, to, count: 0 };
+    suggestion.count++;
+    counts.set(id, suggestion);
   }
 
-  return { transcription, openaiResponse };
+  const covered = new Set(
+    Object.keys(config.replacements).map((from) => from.toLowerCase())
+  );
+  return [...counts.entries()]
+    .filter(
+      ([id, { from, count }]) =>
+        count >= 2 &&
+        !dismissed.includes(id) &&
+        !covered.has(from.toLowerCase())
+    )
+    .map(([, suggestion]) => suggestion)
+    .sort((a, b) => b.count - a.count);
 }
 
-// Global shortcut setup instructions
-function showShortcutInstructions(): void {
-  const scriptPath = new URL(import.meta.url).pathname;
-  const command = `${Deno.execPath()} run --allow-all "${scriptPath}" toggle`;
+async function suggestionsCommand(
+  action?: string,
+  target?: string
+): Promise<void> {
+  const config = await loadSettings();
+  const suggestions = await findSuggestions(config);
 
-  const platformInstructions =
-    Deno.build.os === "linux"
-      ? `🐧 Linux Setup:
-1. For i3: Add to ~/.config/i3/config:
-   bindsym --release Mod4+Shift+space exec "${command}"
-   (Note: --release flag prevents multiple fires)
-   Then run: i3-msg reload
+  if (!action) {
+    if (suggestions.length === 0) {
+      console.log("No suggestions yet - fix dictations with `typr correct`");
+      return;
+    }
+    suggestions.forEach(({ from, to, count }, index) => {
+      console.log(`${index + 1}. "${from}" → "${to}" (corrected ${count}×)`);
+    });
+    console.log("\nApprove with `typr suggestions accept <n|all>`");
+    return;
+  }
 
-2. For GNOME: Settings > Keyboard > Custom Shortcuts
-   - Name: Typr Toggle
-   - Command: ${command}
-   - Shortcut: Super+Shift+Space
-   (GNOME typically fires on key release by default)
+  const picked =
+    target === "all"
+      ? suggestions
+      : suggestions.filter((_, index) => index + 1 === Number(target));
+  if (picked.length === 0 || !["accept", "dismiss"].includes(action)) {
+    console.error("Usage: typr suggestions [accept|dismiss <n|all>]");
+    Deno.exit(1);
+  }
 
-3. For KDE: System Settings > Shortcuts > Custom Shortcuts`
-      : Deno.build.os === "darwin"
-      ? `🍎 macOS Setup:
-1. Install a shortcut manager like Karabiner-Elements or BetterTouchTool
-2. Or use Automator + System Preferences:
-   - Create new 'Quick Action' in Automator
-   - Add 'Run Shell Script' action
-   - Script: ${command}
-   - Save as 'Typr Toggle'
-   - System Preferences > Keyboard > Shortcuts > Services
-   - Assign ⌘⇧Space to 'Typr Toggle'`
-      : "Manual setup required for your OS";
+  if (action === "accept") {
+    for (const { from, to } of picked) config.replacements[from] = to;
+    await saveSettings(config);
+    console.log(`✅ Added ${picked.length} replacement rule(s)`);
+  } else {
+    const corrections = await loadCorrections();
+    corrections.dismissed.push(
+      ...picked.map(({ from, to }) => `${from.toLowerCase()} → ${to}`)
+    );
+    await saveCorrections(corrections);
+    console.log(`🗑️ Dismissed ${picked.length} suggestion(s)`);
+  }
+}
 
-  console.log(`🔗 Global Shortcut Setup
-========================
+async function switchMode(name?: string): Promise<void> {
+  const config = await loadSettings();
+  const names = [...new Set(["default", ...Object.keys(config.modes)])];
 
-To enable toggle recording, bind this command to a keyboard shortcut:
-Command: ${command}
+  if (!name) {
+    for (const mode of names) {
+      console.log(`${mode === config.activeMode ? "▶" : " "} ${mode}`);
+    }
+    return;
+  }
 
-${platformInstructions}
+  // Cycling is meant to be bound to a hotkey, so report via notification
+  if (name === "next") {
+    const next = names[(names.indexOf(config.activeMode) + 1) % names.length];
+    await saveSettings({ ...config, activeMode: next });
+    await notify(`🎛️ Mode: ${next}`, "low");
+    console.log(`✅ Active mode: ${next}`);
+    return;
+  }
 
-💡 How it works:
-   - First press: Starts recording
-   - Second press: Stops recording and types the result
+  if (name !== "default" && !(name in config.modes)) {
+    console.error(`❌ Unknown mode "${name}". Define it under "modes" first.`);
+    Deno.exit(1);
+  }
 
-📊 Monitor logs in real-time:
-   tail -f ${LOG_FILE}    # Watch logs`);
+  await saveSettings({ ...config, activeMode: name });
+  console.log(`✅ Active mode: ${name}`);
 }
 
-async function handleToggleRecording(): Promise<void> {
-  // Check if there's already a recording (ffmpeg) process running
-  const ffmpegPid = await getState("ffmpegPid");
+// On/off switches for menu-bar items and hotkeys, e.g. going offline on a
+// plane. Saved settings, so the next dictation picks them up.
+async function quickSetting(
+  name: "local" | "llm",
+  value?: string
+): Promise<void> {
+  const config = await loadSettings();
+  const current = name === "local" ? config.useLocalWhisper : !config.skipLLM;
+  const label = name === "local" ? "Local Whisper" : "LLM editing";
 
-  if (ffmpegPid) {
-    // Verify the process is actually running
-    try {
-      const command = new Deno.Command("ps", { args: ["-p", ffmpegPid] });
-      const result = await command.output();
-      if (!result.success) {
-        // Process doesn't exist, clear stale state
-        await logToFile("INFO", `Stale PID ${ffmpegPid} found, clearing state`);
-        await clearState();
-      }
-    } catch {
-      // ps command failed, assume process doesn't exist
-      await logToFile(
-        "INFO",
-        `Could not check PID ${ffmpegPid}, clearing state`
-      );
-      await clearState();
-      // Continue to start new recording
-    }
+  if (value === undefined) {
+    console.log(`${label}: ${current ? "on" : "off"}`);
+    return;
+  }
+  if (value !== "on" && value !== "off" && value !== "toggle") {
+    console.error(`❌ Usage: typr ${name} [on|off|toggle]`);
+    Deno.exit(1);
   }
 
-  if (ffmpegPid && (await getState("ffmpegPid"))) {
-    // Re-check after potential cleanup
-    // Second toggle: Kill the ffmpeg process directly
-    await notify("⏹️ Stopping recording...", "low");
-    await playDoubleBeep();
-    try {
-      await logToFile("INFO", `Sending SIGTERM to ffmpeg process ${ffmpegPid}`);
-      Deno.kill(parseInt(ffmpegPid), "SIGTERM");
-    } catch {
-      // Process may have already ended, which is fine
-      await logToFile(
-        "INFO",
-        `Process ${ffmpegPid} already ended or not found`
-      );
+  const next = value === "toggle" ? !current : value === "on";
+  if (name === "local") {
+    await saveSettings({ ...config, useLocalWhisper: next });
+    if (next && !(await isWhisperAvailable(config))) {
+      console.error("⚠️ Local Whisper isn't available - run typr check");
     }
-    return;
+  } else {
+    await saveSettings({ ...config, skipLLM: !next });
   }
+  await notify(`${next ? "✅" : "⏹️"} ${label} ${next ? "on" : "off"}`, "low");
+  console.log(`✅ ${label}: ${next ? "on" : "off"}`);
+}
 
-  // First toggle: Start recording process
-  await clearState();
+async function showUsage(): Promise<void> {
+  const config = await loadSettings();
+  const usage = await loadUsage();
+  const keys = getOpenAIKeys(config);
 
-  const recordedPath = await startRecording();
-  if (!recordedPath) {
-    await logToFile("ERROR", "Failed to start recording");
+  if (keys.length === 0) {
+    console.log("No OpenAI API keys configured");
     return;
   }
 
-  // Recording completed (ffmpeg terminated), process the audio
-  await clearState(); // Clear the ffmpeg PID
-  await logToFile("INFO", "🔄 Processing recorded audio...");
-  try {
-    const result = await processAudioFile(recordedPath);
-    await notify("✅ Typing result...", "low");
-    await typeText(result.openaiResponse);
-    await notify("🎯 Done!", "low");
-  } catch (error) {
-    await logToFile("ERROR", "Failed to process audio", error);
-    await notify("❌ Processing failed", "critical");
+  console.log(`🔑 OpenAI key usage (${config.keyRotation})`);
+  for (const apiKey of keys) {
+    const entry = keyUsageSchema.parse(usage.keys[await keyId(apiKey)] ?? {});
+    console.log(
+      `  ${keyLabel(apiKey)}  requests: ${entry.requests}  failures: ${
+        entry.failures
+      }  rate limited: ${entry.rateLimited}  last used: ${
+        entry.lastUsed || "never"
+      }`
+    );
   }
 }
 
-// CLI Commands
-async function showConfig(): Promise<void> {
-  const config = await loadSettings();
-  console.log(config);
-}
-
 // Main CLI handler
 async function main(): Promise<void> {
   const args = parseArgs(Deno.args);
@@ -603,25 +7654,168 @@ async function main(): Promise<void> {
 
   switch (command) {
     case "toggle":
-      await handleToggleRecording();
+      // `--countdown` on its own means two seconds
+      await handleToggleRecording(
+        args.countdown === true ? 2 : Number(args.countdown ?? 0),
+        { copyOnly: !!args["copy-only"], skipLLM: !!args["skip-llm"] }
+      );
       break;
     case "config":
-      await showConfig();
+      await showConfig(!!args.check);
       break;
     case "shortcuts":
       showShortcutInstructions();
       break;
+    case "serve":
+      await runServer();
+      break;
+    case "usage":
+      await showUsage();
+      break;
+    case "history":
+      await showHistory(Number(args._[1] ?? 10));
+      break;
+    case "sync":
+      await syncHistory();
+      break;
+    case "setup":
+      await runSetup();
+      break;
+    case "listen":
+      await listenForWakeWord();
+      break;
+    case "captions":
+      await runCaptions();
+      break;
+    case "calibrate":
+      await calibrateNoiseGate();
+      break;
+    case "diagnostics":
+      await showDiagnostics();
+      break;
+    case "check":
+      await checkSetup();
+      break;
+    case "undo":
+      await undoLastDictation();
+      break;
+    case "open-logs":
+      await openLogs();
+      break;
+    case "logs":
+      await showLogs(Number(args.n ?? 50));
+      break;
+    case "status":
+      // Status bars poll the phase every second; skip the slow checks
+      if (args.phase) {
+        console.log(await getPhase());
+      } else {
+        await showStatus();
+      }
+      break;
+    case "preview-prompt":
+      await previewPrompt();
+      break;
+    case "scratchpad":
+      await scratchpadCommand(args._[1] as string | undefined);
+      break;
+    case "reedit":
+      await reeditHistoryEntry(args._[1] as string | undefined);
+      break;
+    case "prompts":
+      await promptsCommand(
+        args._[1] as string | undefined,
+        args._.slice(2).map(String)
+      );
+      break;
+    case "session":
+      await sessionCommand(
+        args._[1] as string | undefined,
+        args._.slice(2).join(" ") || undefined,
+        !!args.summarize
+      );
+      break;
+    case "pending":
+      await pendingCommand(args._[1] as string | undefined);
+      break;
+    case "flush":
+      await flushPending();
+      break;
+    case "correct":
+      await correctHistoryEntry(args._[1] as string | undefined);
+      break;
+    case "suggestions":
+      await suggestionsCommand(
+        args._[1] as string | undefined,
+        args._[2] === undefined ? undefined : String(args._[2])
+      );
+      break;
+    case "transcribe":
+      await transcribeFile(
+        args._[1] as string | undefined,
+        !!args.srt,
+        args.output === undefined ? undefined : String(args.output),
+        !!args.force
+      );
+      break;
+    case "import":
+      await importCommand(args._[1] as string | undefined, !!args.watch);
+      break;
+    case "review":
+      await reviewCommand(
+        args._[1] as string | undefined,
+        args._.slice(2).map(String)
+      );
+      break;
+    case "mode":
+      await switchMode(args._[1] as string | undefined);
+      break;
+    case "local":
+    case "llm":
+      await quickSetting(command, args._[1] as string | undefined);
+      break;
     default:
       console.log(`Typr - Elegant dictation with press-and-hold recording
 
 Usage:
-  typr config     - Show current configuration
+  typr setup      - Guided first-run setup with a test dictation
+  typr config [--check] - Show (or validate) the current configuration
+  typr status [--phase] - Print a JSON status snapshot (or just the phase)
+  typr preview-prompt - Show the composed transcription prompt
   typr shortcuts  - Show keyboard shortcut setup instructions
-  typr toggle     - Toggle recording (used by shortcuts)
+  typr toggle [--countdown N] [--copy-only] [--skip-llm] - Toggle recording
+  typr serve      - Stream transcripts to local WebSocket subscribers
+  typr usage      - Show per-key OpenAI usage
+  typr mode [m]   - List modes, switch mode, or cycle with "next"
+  typr local [on|off|toggle] - Transcribe with local Whisper (offline)
+  typr llm [on|off|toggle] - Turn the LLM editing step on or off
+  typr check      - Check that the transcription provider is reachable
+  typr calibrate  - Measure ambient noise and set the noise gate
+  typr listen     - Start dictations hands-free with a wake word
+  typr captions   - Show live captions of the microphone or call audio
+  typr transcribe <file> [--srt] [--output f] [--force] - Transcribe audio
+    or video; --force skips the transcript cache
+  typr import [folder] [--watch] - Transcribe new voice memos into history
+  typr review [waveform|trim <start> <end>|send|discard] - Trim, then send
+  typr undo       - Remove the last dictation from the focused app
+  typr open-logs  - Open the log files for attaching to bug reports
+  typr logs [-n N] - Show the last N log lines
+  typr history    - Show recent dictations
+  typr reedit [id] - Re-run the editing step on a dictation
+  typr correct [id] - Fix a dictation in $EDITOR and learn from it
+  typr prompts [show|history|edit|revert|test] <name>[@version] [id] -
+    Manage saved LLM prompts and try one on a past dictation
+  typr pending [count|clear] - List results held back because focus moved
+  typr flush      - Type all pending results into the focused app
+  typr session [start [name]|end [--summarize]|status] - Collect dictations
+  typr suggestions [accept|dismiss <n|all>] - Review learned replacements
+  typr scratchpad [open|copy|insert|clear] - Work with the scratchpad
+  typr sync       - Merge history and modes via the encrypted sync folder
+  typr diagnostics - Show per-stage latency averages
 
 Quick Start:
-  1. Add your OpenAI key to ~/.typr-settings.json
-  2. typr shortcuts   # Setup keyboard shortcut
+  1. typr setup       # Checks permissions and runs a test dictation
+  2. Bind the shortcut it shows you
   3. Use your shortcut to record!
 
 How it works:
@@ -631,6 +7825,17 @@ How it works:
   }
 }
 
+// The pipeline entry points typr_test.ts drives end to end
+export { deliverResult, loadActiveSettings, processAudioFile };
+
 if (import.meta.main) {
-  await main();
+  try {
+    await main();
+  } catch (error) {
+    if (!(error instanceof SettingsError)) throw error;
+    // Most commands run from a shortcut, where nobody sees the console
+    console.error(`❌ ${error.message}`);
+    await notify("⚙️ Settings file has errors - run typr config --check");
+    Deno.exit(1);
+  }
 }
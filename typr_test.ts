@@ -0,0 +1,142 @@
+// End-to-end tests of the dictation pipeline: a fixture recording goes
+// through a mock transcription provider, the formatting passes and the
+// capture typing backend, without touching OpenAI or the keyboard.
+//
+//   deno test --allow-all
+import { assertEquals, assertStringIncludes } from "jsr:@std/assert";
+import { join } from "jsr:@std/path";
+
+// typr.ts resolves its dotfiles from HOME when it loads, so point HOME at a
+// scratch directory before importing it
+const home = await Deno.makeTempDir({ prefix: "typr-test-" });
+Deno.env.set("HOME", home);
+const captureFile = join(home, "typed.txt");
+Deno.env.set("TYPR_CAPTURE_FILE", captureFile);
+
+const typr = await import("./typr.ts");
+
+const FIXTURE = new URL("./fixtures/hello.wav", import.meta.url).pathname;
+
+// An OpenAI-compatible transcription endpoint that answers with whatever the
+// current test queued up, and remembers the prompt it was sent
+type MockProvider = {
+  url: string;
+  respond: (text: string) => void;
+  lastPrompt: () => string;
+  close: () => Promise<void>;
+};
+
+function startMockProvider(): MockProvider {
+  let transcript = "";
+  let prompt = "";
+  const server = Deno.serve(
+    { port: 0, hostname: "127.0.0.1", onListen: () => {} },
+    async (request) => {
+      const form = await request.formData();
+      prompt = String(form.get("prompt") ?? "");
+      return new Response(transcript);
+    }
+  );
+  return {
+    url: `http://127.0.0.1:${server.addr.port}/v1`,
+    respond: (text) => (transcript = text),
+    lastPrompt: () => prompt,
+    close: () => server.shutdown(),
+  };
+}
+
+async function writeSettings(settings: Record<string, unknown>) {
+  await Deno.writeTextFile(
+    join(home, ".typr-settings.json"),
+    JSON.stringify(settings, null, 2)
+  );
+}
+
+// Settings that keep the pipeline offline: the mock provider, no LLM key,
+// no ffmpeg-based steps (speech detection, chunking, re-encoding) and no
+// transcript cache, since every test sends the same fixture
+function baseSettings(provider: MockProvider) {
+  return {
+    transcriptionProvider: "selfhosted",
+    selfHostedURL: provider.url,
+    speechThresholdDb: 0,
+    longAudioChunkSeconds: 0,
+    historyLimit: 0,
+    transcriptCache: false,
+    focusChangeBehavior: "ignore",
+  };
+}
+
+async function dictate(): Promise<string> {
+  await Deno.writeTextFile(captureFile, "");
+  const result = await typr.processAudioFile(FIXTURE);
+  if (!result.handled) {
+    const config = await typr.loadActiveSettings();
+    await typr.deliverResult(result.openaiResponse, config, null, result.email);
+  }
+  return await Deno.readTextFile(captureFile);
+}
+
+Deno.test("pipeline types the formatted transcript", async () => {
+  const provider = startMockProvider();
+  try {
+    await writeSettings({
+      ...baseSettings(provider),
+      normalizeAddresses: true,
+    });
+    provider.respond(
+      "hello world slap send it to john dot smith at gmail dot com"
+    );
+    assertEquals(
+      await dictate(),
+      "hello world\nsend it to john.smith@gmail.com"
+    );
+  } finally {
+    await provider.close();
+  }
+});
+
+Deno.test("vocabulary reaches the transcription prompt", async () => {
+  const provider = startMockProvider();
+  try {
+    await writeSettings({
+      ...baseSettings(provider),
+      whisperPrompt: "",
+      vocabulary: ["Typr", "Kubernetes"],
+    });
+    provider.respond("Deploying Typr to Kubernetes today");
+    assertEquals(await dictate(), "Deploying Typr to Kubernetes today");
+    assertStringIncludes(
+      provider.lastPrompt(),
+      "Vocabulary: Typr, Kubernetes."
+    );
+  } finally {
+    await provider.close();
+  }
+});
+
+Deno.test("known hallucinations are not typed", async () => {
+  const provider = startMockProvider();
+  try {
+    await writeSettings(baseSettings(provider));
+    provider.respond("Thank you for watching.");
+    assertEquals(await dictate(), "");
+  } finally {
+    await provider.close();
+  }
+});
+
+Deno.test("modes change the output", async () => {
+  const provider = startMockProvider();
+  try {
+    await writeSettings({
+      ...baseSettings(provider),
+      activeMode: "terminal",
+      modes: { terminal: { casing: "lower", punctuation: false } },
+    });
+    provider.respond("Run the Build, then deploy.");
+    assertEquals(await dictate(), "run the build then deploy");
+  } finally {
+    await provider.close();
+  }
+});